@@ -1,6 +1,16 @@
+import glob
 import shutil
+import subprocess
 import os
 
+# The default instance's unit and those of named instances (tynkerbase-agent-<name>.service)
+unit_paths = glob.glob('/etc/systemd/system/tynkerbase-agent*.service')
+for unit_path in unit_paths:
+    subprocess.run(['systemctl', 'disable', '--now', os.path.basename(unit_path)])
+    os.remove(unit_path)
+if unit_paths:
+    subprocess.run('systemctl daemon-reload'.split())
+
 if os.path.exists('/usr/local/bin/tyb_agent'):
     os.remove('/usr/local/bin/tyb_agent')
 
@@ -0,0 +1,184 @@
+use crate::{
+    auth_utils::{self, RequestIdentity},
+    config_utils,
+};
+use anyhow::{anyhow, Result};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+const MAX_LOG_BYTES: u64 = 5_000_000;
+const MAX_ROTATED_LOGS: usize = 5;
+
+// Serializes writes (and rotations) to the audit log
+static AUDIT_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub client_ip: Option<String>,
+    pub identity: Option<String>,
+    pub method: String,
+    pub route: String,
+    pub project: Option<String>,
+    pub status: u16,
+}
+
+/// Lets routes that don't take the project name as a query parameter
+/// (e.g. ones that read it from the body) tell the audit log which project they touched.
+pub struct AuditProject(pub Mutex<Option<String>>);
+
+impl AuditProject {
+    pub fn set(&self, proj_name: &str) {
+        if let Ok(mut lock) = self.0.lock() {
+            *lock = Some(proj_name.to_string());
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for &'r AuditProject {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        rocket::outcome::Outcome::Success(req.local_cache(|| AuditProject(Mutex::new(None))))
+    }
+}
+
+fn get_log_dir() -> String {
+    format!("{}/logs", config_utils::root_dir())
+}
+
+fn get_log_path(index: usize) -> String {
+    match index {
+        0 => format!("{}/audit.log", get_log_dir()),
+        i => format!("{}/audit.log.{}", get_log_dir(), i),
+    }
+}
+
+fn rotate() -> Result<()> {
+    let oldest = get_log_path(MAX_ROTATED_LOGS);
+    if Path::new(&oldest).exists() {
+        fs::remove_file(&oldest).map_err(|e| anyhow!("Failed to remove `{oldest}` -> {e}"))?;
+    }
+    for i in (0..MAX_ROTATED_LOGS).rev() {
+        let from = get_log_path(i);
+        if Path::new(&from).exists() {
+            fs::rename(&from, get_log_path(i + 1))
+                .map_err(|e| anyhow!("Failed to rotate `{from}` -> {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn append(entry: &AuditEntry) -> Result<()> {
+    let _guard = AUDIT_LOCK.lock().map_err(|e| anyhow!("Audit log lock poisoned -> {e}"))?;
+
+    let dir = get_log_dir();
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+
+    let path = get_log_path(0);
+    if fs::metadata(&path).map(|m| m.len()).unwrap_or(0) >= MAX_LOG_BYTES {
+        rotate()?;
+    }
+
+    let mut line = serde_json::to_string(entry)
+        .map_err(|e| anyhow!("Failed to serialize audit entry -> {e}"))?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| anyhow!("Failed to open `{path}` -> {e}"))?;
+    file.write_all(line.as_bytes())
+        .map_err(|e| anyhow!("Failed to write to `{path}` -> {e}"))
+}
+
+/// Returns the entries (oldest first) within `[since, until]` that touched `project`.
+pub fn query(since: Option<u64>, until: Option<u64>, project: Option<&str>) -> Result<Vec<AuditEntry>> {
+    let _guard = AUDIT_LOCK.lock().map_err(|e| anyhow!("Audit log lock poisoned -> {e}"))?;
+
+    let mut entries = vec![];
+    for i in (0..=MAX_ROTATED_LOGS).rev() {
+        let text = match fs::read_to_string(get_log_path(i)) {
+            Ok(t) => t,
+            _ => continue,
+        };
+        for line in text.lines() {
+            let entry: AuditEntry = match serde_json::from_str(line) {
+                Ok(e) => e,
+                _ => continue,
+            };
+            if since.is_some_and(|s| entry.timestamp < s) || until.is_some_and(|u| entry.timestamp > u) {
+                continue;
+            }
+            if project.is_some() && entry.project.as_deref() != project {
+                continue;
+            }
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Records every call to one of `routes` (matched by handler name) in the audit log.
+pub struct AuditFairing {
+    pub routes: &'static [&'static str],
+}
+
+#[rocket::async_trait]
+impl Fairing for AuditFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit Log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let route = match req.route() {
+            Some(r) => r,
+            None => return,
+        };
+        let is_audited = route
+            .name
+            .as_ref()
+            .is_some_and(|n| self.routes.contains(&n.as_ref()));
+        if !is_audited {
+            return;
+        }
+
+        let project = req
+            .local_cache(|| AuditProject(Mutex::new(None)))
+            .0
+            .lock()
+            .ok()
+            .and_then(|p| p.clone())
+            .or_else(|| req.query_value::<String>("name").and_then(|n| n.ok()));
+
+        let entry = AuditEntry {
+            timestamp: auth_utils::now_secs(),
+            client_ip: auth_utils::client_ip(req).map(|ip| ip.to_string()),
+            identity: req.local_cache(|| RequestIdentity(None)).0.clone(),
+            method: req.method().as_str().to_string(),
+            route: req.uri().path().to_string(),
+            project,
+            status: res.status().code,
+        };
+
+        if let Err(e) = append(&entry) {
+            log::error!("Failed to write audit log -> {}", e);
+        }
+    }
+}
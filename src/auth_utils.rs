@@ -0,0 +1,295 @@
+use crate::{config_utils, ngrok_utils};
+use anyhow::{anyhow, Result};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    net::IpAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tynkerbase_universal::crypt_utils::hash_utils;
+
+const TOKEN_LEN: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Permission {
+    ReadOnly,
+    Deploy,
+    Admin,
+}
+
+impl Permission {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "read-only" | "readonly" | "read" => Ok(Self::ReadOnly),
+            "deploy" => Ok(Self::Deploy),
+            "admin" => Ok(Self::Admin),
+            _ => Err(anyhow!(
+                "`{}` is not a valid permission (expected `read-only`, `deploy` or `admin`)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopedToken {
+    pub name: String,
+    pub token_sha256: String,
+    pub permission: Permission,
+    // `None` grants access to every project on the node
+    pub projects: Option<Vec<String>>,
+    pub created_at: u64,
+}
+
+/// Who a request was authenticated as and what it is allowed to touch.
+#[derive(Debug, Clone, Serialize)]
+pub struct Identity {
+    pub name: String,
+    pub permission: Permission,
+    pub projects: Option<Vec<String>>,
+}
+
+impl Identity {
+    /// Identity of the node's own API key, which has full control.
+    pub fn master() -> Self {
+        Identity {
+            name: "master".to_string(),
+            permission: Permission::Admin,
+            projects: None,
+        }
+    }
+
+    pub fn can_access(&self, proj_name: &str) -> bool {
+        match &self.projects {
+            Some(projects) => projects.iter().any(|p| p == proj_name),
+            None => true,
+        }
+    }
+
+    pub fn check(&self, required: Permission, proj_name: Option<&str>) -> Result<()> {
+        if self.permission < required {
+            return Err(anyhow!(
+                "Token `{}` has {:?} permission, but {:?} is required",
+                self.name,
+                self.permission,
+                required
+            ));
+        }
+        if let Some(proj_name) = proj_name {
+            if !self.can_access(proj_name) {
+                return Err(anyhow!(
+                    "Token `{}` is not scoped to project `{}`",
+                    self.name,
+                    proj_name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `check`, but also requires the identity to not be limited to a set of projects.
+    /// Used for operations that affect the whole node.
+    pub fn check_node_wide(&self, required: Permission) -> Result<()> {
+        self.check(required, None)?;
+        if self.projects.is_some() {
+            return Err(anyhow!(
+                "Token `{}` is scoped to specific projects and cannot perform node-wide operations",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Name of the identity a request authenticated as, cached on the request for logging.
+pub struct RequestIdentity(pub Option<String>);
+
+impl From<&ScopedToken> for Identity {
+    fn from(tok: &ScopedToken) -> Self {
+        Identity {
+            name: tok.name.clone(),
+            permission: tok.permission,
+            projects: tok.projects.clone(),
+        }
+    }
+}
+
+/// A client certificate issued by the agent's client CA, used for mutual TLS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientCert {
+    pub name: String,
+    // lowercase hex without leading zeros
+    pub serial: String,
+    pub permission: Permission,
+    pub projects: Option<Vec<String>>,
+    pub created_at: u64,
+    pub revoked: bool,
+}
+
+impl From<&ClientCert> for Identity {
+    fn from(cert: &ClientCert) -> Self {
+        Identity {
+            name: cert.name.clone(),
+            permission: cert.permission,
+            projects: cert.projects.clone(),
+        }
+    }
+}
+
+fn get_tokens_path() -> String {
+    format!("{}/data/tokens.bin", config_utils::root_dir())
+}
+
+pub fn load_tokens() -> Vec<ScopedToken> {
+    let path = get_tokens_path();
+    match fs::read(&path) {
+        Ok(bin) => bincode::deserialize(&bin).unwrap_or_else(|e| {
+            log::warn!("Failed to deserialize `{}`, ignoring it -> {}", path, e);
+            vec![]
+        }),
+        _ => vec![],
+    }
+}
+
+pub fn save_tokens(tokens: &[ScopedToken]) -> Result<()> {
+    let dir = format!("{}/data", config_utils::root_dir());
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+    let bin = bincode::serialize(tokens).map_err(|e| anyhow!("Failed to serialize tokens -> {e}"))?;
+    fs::write(get_tokens_path(), bin).map_err(|e| anyhow!("Failed to write tokens -> {e}"))
+}
+
+fn get_client_certs_path() -> String {
+    format!("{}/data/client-certs.bin", config_utils::root_dir())
+}
+
+pub fn load_client_certs() -> Vec<ClientCert> {
+    let path = get_client_certs_path();
+    match fs::read(&path) {
+        Ok(bin) => bincode::deserialize(&bin).unwrap_or_else(|e| {
+            log::warn!("Failed to deserialize `{}`, ignoring it -> {}", path, e);
+            vec![]
+        }),
+        _ => vec![],
+    }
+}
+
+pub fn save_client_certs(certs: &[ClientCert]) -> Result<()> {
+    let dir = format!("{}/data", config_utils::root_dir());
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+    let bin = bincode::serialize(certs)
+        .map_err(|e| anyhow!("Failed to serialize client certificates -> {e}"))?;
+    fs::write(get_client_certs_path(), bin)
+        .map_err(|e| anyhow!("Failed to write client certificates -> {e}"))
+}
+
+/// Finds the unrevoked client certificate with the given serial and common name.
+pub fn find_client_cert<'a>(
+    certs: &'a [ClientCert],
+    serial: &str,
+    common_name: &str,
+) -> Option<&'a ClientCert> {
+    let serial = serial.trim_start_matches('0').to_ascii_lowercase();
+    certs
+        .iter()
+        .find(|c| !c.revoked && c.serial == serial && c.name == common_name)
+}
+
+pub fn validate_identity_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > 64
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "Name `{}` is invalid, only up to 64 letters, digits, `-` and `_` are allowed",
+            name
+        ));
+    }
+    if name == Identity::master().name {
+        return Err(anyhow!("Name `{}` is reserved", name));
+    }
+    Ok(())
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Creates a new token and returns it in plain text along with the record to store.
+/// Only the sha256 of the token is ever persisted.
+pub fn gen_token(
+    name: &str,
+    permission: Permission,
+    projects: Option<Vec<String>>,
+) -> Result<(String, ScopedToken)> {
+    validate_identity_name(name)?;
+
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .map(char::from)
+        .collect();
+
+    let created_at = now_secs();
+
+    let record = ScopedToken {
+        name: name.to_string(),
+        token_sha256: hash_utils::sha256(&token),
+        permission,
+        projects,
+        created_at,
+    };
+
+    Ok((token, record))
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so response
+/// timings don't leak how much of a guessed key was correct.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn find_token<'a>(tokens: &'a [ScopedToken], key: &str) -> Option<&'a ScopedToken> {
+    let key_sha256 = hash_utils::sha256(key);
+    // Check every token rather than stopping early to keep timing uniform
+    let mut found = None;
+    for tok in tokens {
+        if constant_time_eq(tok.token_sha256.as_bytes(), key_sha256.as_bytes()) {
+            found = Some(tok);
+        }
+    }
+    found
+}
+
+// Resolves the address of the client that sent the request. Requests tunneled through ngrok
+// arrive from localhost, so in that case the original address is taken from `X-Forwarded-For`.
+// Only the last entry is used, the one ngrok appended; anything before it came from the client.
+// Without a tunnel the header isn't trusted at all.
+pub fn client_ip(req: &Request<'_>) -> Option<IpAddr> {
+    let remote = req.remote()?.ip();
+    if remote.is_loopback() && ngrok_utils::tunnel_running() {
+        let forwarded = req
+            .headers()
+            .get_one("X-Forwarded-For")
+            .and_then(|h| h.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+        if let Some(ip) = forwarded {
+            return Some(ip);
+        }
+    }
+    Some(remote)
+}
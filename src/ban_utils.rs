@@ -0,0 +1,131 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// Number of failed auth attempts tolerated before a client gets banned
+const MAX_FAILURES: u32 = 5;
+// Failures older than this are forgotten
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+// First ban lasts this long, every subsequent ban doubles it
+const BASE_BAN: Duration = Duration::from_secs(60);
+const MAX_BAN: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    ban_count: u32,
+    banned_until: Option<Instant>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BanInfo {
+    pub ip: IpAddr,
+    pub failures: u32,
+    pub ban_count: u32,
+    pub remaining_secs: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct BanTracker {
+    records: HashMap<IpAddr, FailureRecord>,
+}
+
+impl BanTracker {
+    /// Returns how much longer `ip` is banned for, if it is banned.
+    pub fn ban_remaining(&self, ip: &IpAddr) -> Option<Duration> {
+        let until = self.records.get(ip)?.banned_until?;
+        let now = Instant::now();
+        if until > now {
+            return Some(until - now);
+        }
+        None
+    }
+
+    pub fn has_failures(&self, ip: &IpAddr) -> bool {
+        self.records.get(ip).is_some_and(|r| r.failures > 0)
+    }
+
+    /// Records a failed attempt and returns the length of the ban if this failure triggered one.
+    pub fn record_failure(&mut self, ip: IpAddr) -> Option<Duration> {
+        self.prune();
+
+        let now = Instant::now();
+        let rec = self.records.entry(ip).or_insert(FailureRecord {
+            failures: 0,
+            last_failure: now,
+            ban_count: 0,
+            banned_until: None,
+        });
+
+        if now.duration_since(rec.last_failure) > FAILURE_WINDOW {
+            rec.failures = 0;
+        }
+        rec.failures += 1;
+        rec.last_failure = now;
+
+        if rec.failures < MAX_FAILURES {
+            return None;
+        }
+
+        let ban = BASE_BAN
+            .checked_mul(2u32.saturating_pow(rec.ban_count))
+            .unwrap_or(MAX_BAN)
+            .min(MAX_BAN);
+        rec.ban_count += 1;
+        rec.failures = 0;
+        rec.banned_until = Some(now + ban);
+        Some(ban)
+    }
+
+    pub fn record_success(&mut self, ip: &IpAddr) {
+        if let Some(rec) = self.records.get_mut(ip) {
+            rec.failures = 0;
+        }
+    }
+
+    pub fn list_bans(&self) -> Vec<BanInfo> {
+        self.records
+            .iter()
+            .filter_map(|(ip, rec)| {
+                let remaining = self.ban_remaining(ip)?;
+                Some(BanInfo {
+                    ip: *ip,
+                    failures: rec.failures,
+                    ban_count: rec.ban_count,
+                    remaining_secs: remaining.as_secs(),
+                })
+            })
+            .collect()
+    }
+
+    /// Lifts the ban on `ip` (or on every client if `None`) and returns how many bans were lifted.
+    pub fn clear(&mut self, ip: Option<&IpAddr>) -> usize {
+        let cleared = match ip {
+            Some(ip) => match self.ban_remaining(ip) {
+                Some(_) => 1,
+                None => 0,
+            },
+            None => self.list_bans().len(),
+        };
+        match ip {
+            Some(ip) => {
+                self.records.remove(ip);
+            }
+            None => self.records.clear(),
+        }
+        cleared
+    }
+
+    // Forget clients that aren't banned and haven't failed recently so the map can't grow forever.
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.records.retain(|_, rec| {
+            rec.banned_until.is_some_and(|u| u > now)
+                || now.duration_since(rec.last_failure) <= FAILURE_WINDOW
+        });
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{
+    auth_utils, config_utils,
+    control_plane::{self, LoginResult},
+    dep_utils, docker_utils,
+    input_utils::{Input, StartupInputs},
+    node_utils, proj_utils, session_utils, tls_utils,
+};
+use anyhow::{anyhow, Result};
+use std::{fs, path::Path, process::Command as StdCommand};
+use tynkerbase_universal::crypt_utils::hash_utils;
+
+pub const USAGE: &str = "\
+Usage: tyb_agent [COMMAND] [OPTIONS]
+
+Commands:
+    run             Log in and serve the agent API (default)
+    login           Log in, register this node and cache the session
+    logout          Delete the cached session
+    reset-node      Forget this node's id and name so it is registered again on the next login
+    gen-cert        (Re)generate the self signed TLS certificate
+    status          Show the state of this node
+    node show       Show this node's id and name
+    node rename <name>
+                    Rename this node (a running agent picks it up on restart)
+    doctor          Check the agent's dependencies and settings
+    config show     Print the effective config (secrets redacted)
+    install-service Install and start a systemd service running the agent
+                    (with --print the unit is printed instead)
+    help            Print this message
+
+Options:
+    --config <path>         Config file to use
+    --instance <name>       Named instance to use, for several agents on one host
+    --credentials <path>    File with the inputs below as `key = value` lines
+    --email <email>
+    --password-file <path>
+    --node-name <name>
+    --ngrok-token <token>
+    --priv                  Don't expose the agent through ngrok (run)
+    --mtls                  Require client certificates, only with --priv (run)
+    --yes                   Accept every confirmation
+    --non-interactive       Never prompt, fail instead
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Login,
+    Logout,
+    ResetNode,
+    GenCert,
+    Status,
+    NodeShow,
+    NodeRename(String),
+    Doctor,
+    ConfigShow,
+    InstallService,
+    Help,
+}
+
+impl Command {
+    pub fn parse(words: &[String]) -> Result<Self> {
+        let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+        let command = match words.as_slice() {
+            [] | ["run"] => Command::Run,
+            ["login"] => Command::Login,
+            ["logout"] => Command::Logout,
+            ["reset-node"] => Command::ResetNode,
+            ["gen-cert"] => Command::GenCert,
+            ["status"] => Command::Status,
+            ["node", "show"] => Command::NodeShow,
+            ["node", "rename", name] => Command::NodeRename(name.to_string()),
+            ["doctor"] => Command::Doctor,
+            ["config", "show"] => Command::ConfigShow,
+            ["install-service"] => Command::InstallService,
+            ["help"] => Command::Help,
+            _ => return Err(anyhow!("Unknown command `{}`\n\n{}", words.join(" "), USAGE)),
+        };
+        Ok(command)
+    }
+}
+
+pub fn logout() -> Result<()> {
+    if session_utils::clear_session()? {
+        println!("Removed the cached session.");
+    } else {
+        println!("Not logged in, there is no cached session.");
+    }
+    Ok(())
+}
+
+/// Deletes the node's id and name (and the session that caches them).
+pub fn reset_node(inputs: &StartupInputs) -> Result<()> {
+    if !node_utils::identity_exists() {
+        println!("This node hasn't been registered yet, nothing to reset.");
+        return Ok(());
+    }
+    if let Ok(Some(identity)) = node_utils::read_identity() {
+        println!("This node is registered as `{}` ({}).", identity.name, identity.node_id);
+    }
+    if !inputs.confirm("Reset it? It will be registered as a new node on the next login (y/n): ")? {
+        return Ok(());
+    }
+
+    node_utils::delete_identity()?;
+    session_utils::clear_session()?;
+    println!("Node reset, run `tyb_agent login` to register it again.");
+    Ok(())
+}
+
+pub fn show_node() -> Result<()> {
+    let identity = node_utils::read_identity()?
+        .ok_or_else(|| anyhow!("This node hasn't been registered yet, run `tyb_agent login`"))?;
+
+    println!("Node id:     {}", identity.node_id);
+    println!("Name:        {}", identity.name);
+    if identity.created_at > 0 {
+        println!("Created at:  {}", identity.created_at);
+    }
+    if let Some(renamed_at) = identity.renamed_at {
+        println!("Renamed at:  {}", renamed_at);
+    }
+    Ok(())
+}
+
+/// Renames the node on disk after checking with the control server that the name is free.
+pub async fn rename_node(inputs: &StartupInputs, name: &str) -> Result<()> {
+    node_utils::validate_node_name(name)?;
+    let mut identity = node_utils::load_identity()?
+        .ok_or_else(|| anyhow!("This node hasn't been registered yet, run `tyb_agent login`"))?;
+    if identity.name == name {
+        println!("This node is already named `{}`.", name);
+        return Ok(());
+    }
+
+    let email = inputs.require(Input::Email, "Enter your email: ")?;
+    let password = inputs.require(Input::Password, "Enter your password: ")?;
+    let pass_sha256 = hash_utils::sha256(&password);
+    match control_plane::get().login(&email, &pass_sha256).await {
+        LoginResult::Success(_) => {}
+        LoginResult::Unauthorized => return Err(anyhow!("Incorrect authorization.")),
+        LoginResult::Unreachable(e) => return Err(anyhow!("Unable to reach the control server -> {e}")),
+    }
+    if control_plane::get().node_name_taken(&email, &pass_sha256, name).await? {
+        return Err(anyhow!("Node name `{}` already exists", name));
+    }
+
+    identity.name = name.to_string();
+    identity.renamed_at = Some(auth_utils::now_secs());
+    node_utils::save_identity(&identity)?;
+
+    // Keep the cached session in sync so an offline start uses the new name too
+    let session_key = session_utils::derive_session_key(&password);
+    if let Ok(mut session) = session_utils::load_session(&session_key) {
+        session.name = identity.name.clone();
+        session_utils::save_session(&session, &session_key)?;
+    }
+
+    println!(
+        "Renamed this node to `{}`. A running agent uses the new name after a restart \
+        (`GET /node/rename` renames it without one).",
+        name
+    );
+    Ok(())
+}
+
+fn ngrok_installed() -> bool {
+    StdCommand::new(&config_utils::get().tunnel.ngrok_path)
+        .arg("version")
+        .output()
+        .is_ok_and(|o| o.status.success())
+}
+
+fn root_dir_writable() -> Result<()> {
+    let dir = format!("{}/data", config_utils::root_dir());
+    fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    let probe = format!("{dir}/.write-test");
+    fs::write(&probe, b"").map_err(|e| anyhow!("Failed to write to `{dir}` -> {e}"))?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+pub async fn status() {
+    let config = config_utils::get();
+
+    let node = match node_utils::read_identity() {
+        Ok(Some(identity)) => format!("{} ({})", identity.name, identity.node_id),
+        Ok(None) => "not registered".to_string(),
+        Err(e) => format!("unknown ({e})"),
+    };
+    let session = match Path::new(&session_utils::get_session_path()).exists() {
+        true => "cached",
+        false => "none",
+    };
+    let tls_path = &tls_utils::get_cert_paths()[0];
+    let tls = match Path::new(tls_path).exists() {
+        true => tls_path.clone(),
+        false => format!("missing ({tls_path})"),
+    };
+    let docker = match docker_utils::get_engine_status().await {
+        Ok(true) => "running".to_string(),
+        Ok(false) => "stopped".to_string(),
+        Err(e) => format!("unknown ({e})"),
+    };
+    let plane = control_plane::get();
+    let server = match plane.ping().await {
+        Ok(_) => format!("reachable ({})", plane.describe()),
+        Err(e) => format!("unreachable ({}) -> {}", plane.describe(), e),
+    };
+    let tunnel = match config.tunnel.enabled {
+        true => "ngrok",
+        false => "disabled",
+    };
+
+    println!("Instance:         {}", config.instance.as_deref().unwrap_or("default"));
+    println!("Node:             {node}");
+    println!("Session:          {session}");
+    println!("TLS certificate:  {tls}");
+    println!("Docker daemon:    {docker}");
+    println!("Control server:   {server}");
+    println!("Tunnel:           {tunnel}");
+    println!("Listening on:     {}:{}", config.server.address, config.server.port);
+    println!("Projects:         {}", proj_utils::read_proj_names().len());
+}
+
+/// Runs every check and prints how to fix the ones that fail. Returns whether all of them passed.
+pub async fn doctor() -> bool {
+    let config = config_utils::get();
+    let mut checks: Vec<(&str, Result<()>)> = vec![];
+
+    checks.push(("Config is valid", Ok(())));
+    checks.push(("Root directory is writable", root_dir_writable()));
+
+    let openssl = match dep_utils::openssl::check_openssl() {
+        true => Ok(()),
+        false => Err(anyhow!("openssl is not installed, install it and run `tyb_agent gen-cert`")),
+    };
+    checks.push(("OpenSSL is installed", openssl));
+
+    let [cert_path, key_path] = tls_utils::get_cert_paths();
+    let tls = match Path::new(&cert_path).exists() && Path::new(&key_path).exists() {
+        true => Ok(()),
+        false if tls_utils::uses_custom_cert() => {
+            Err(anyhow!("`{cert_path}` or `{key_path}` is missing, check the [tls] section of the config"))
+        }
+        false => Err(anyhow!("no certificate in `{cert_path}`, run `tyb_agent gen-cert`")),
+    };
+    checks.push(("TLS certificate exists", tls));
+
+    let docker = match dep_utils::docker::check_docker() {
+        true => Ok(()),
+        false => Err(anyhow!("docker is not installed, `tyb_agent run --yes` installs it")),
+    };
+    let docker_installed = docker.is_ok();
+    checks.push(("Docker is installed", docker));
+    if docker_installed {
+        let daemon = match docker_utils::get_engine_status().await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(anyhow!("the docker daemon is stopped, run `sudo systemctl start docker`")),
+            Err(e) => Err(e),
+        };
+        checks.push(("Docker daemon is running", daemon));
+    }
+
+    if config.tunnel.enabled {
+        let ngrok = match ngrok_installed() {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "`{}` was not found, install ngrok or run with `--priv`",
+                config.tunnel.ngrok_path
+            )),
+        };
+        checks.push(("ngrok is installed", ngrok));
+    }
+
+    let plane = control_plane::get();
+    let server = plane
+        .ping()
+        .await
+        .map_err(|e| anyhow!("can't reach `{}` -> {}", plane.describe(), e));
+    checks.push(("Control server is reachable", server));
+
+    let node = match node_utils::read_identity() {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(anyhow!("run `tyb_agent login` to register this node")),
+        Err(e) => Err(e),
+    };
+    checks.push(("Node is registered", node));
+
+    let mut all_passed = true;
+    for (name, res) in checks {
+        match res {
+            Ok(_) => println!("[ ok ] {name}"),
+            Err(e) => {
+                all_passed = false;
+                println!("[fail] {name}: {e}");
+            }
+        }
+    }
+    all_passed
+}
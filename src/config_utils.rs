@@ -0,0 +1,605 @@
+/*
+Settings the agent is launched with. They are read from a TOML file and can be overridden
+with environment variables, e.g.
+
+    instance = "team-a"           # TYB_INSTANCE, or `--instance <name>`
+
+    [server]
+    address = "0.0.0.0"           # TYB_BIND_ADDRESS
+    port = 7462                   # TYB_PORT
+    upload_limit_mb = 20          # TYB_UPLOAD_LIMIT_MB
+
+    [control_server]
+    kind = "http"                 # TYB_CONTROL_SERVER_KIND, "http" or "local" (see `control_plane`)
+    endpoint = "https://..."      # TYB_SERVER_ENDPOINT
+
+    [paths]
+    root_dir = "/usr/share/tynkerbase-agent"     # TYB_ROOT_DIR
+    projects_dir = "..."                        # TYB_PROJECTS_DIR
+
+    [tls]
+    cert_path = "/etc/ssl/agent-cert.pem"       # TYB_TLS_CERT
+    key_path = "/etc/ssl/agent-key.pem"         # TYB_TLS_KEY
+
+    [tunnel]
+    enabled = true                # TYB_TUNNEL_ENABLED
+    ngrok_path = "ngrok"          # TYB_NGROK_PATH
+    authtoken = "..."             # (see `Input::NgrokToken`)
+    web_addr = "localhost:4040"   # TYB_NGROK_WEB_ADDR
+    startup_timeout_secs = 10.0   # TYB_TUNNEL_TIMEOUT_SECS
+
+    [heartbeat]
+    enabled = true                # TYB_HEARTBEAT_ENABLED
+    interval_secs = 60            # TYB_HEARTBEAT_INTERVAL_SECS
+    max_backoff_secs = 600        # TYB_HEARTBEAT_MAX_BACKOFF_SECS
+
+    [health]
+    min_free_disk_mb = 1024       # TYB_HEALTH_MIN_FREE_DISK_MB, in the projects dir
+    cert_warn_days = 14           # TYB_HEALTH_CERT_WARN_DAYS
+
+    [uploads]
+    max_size_mb = 512             # TYB_UPLOAD_MAX_SIZE_MB, largest chunked upload (see `upload_utils`)
+    session_ttl_secs = 86400      # TYB_UPLOAD_SESSION_TTL_SECS
+
+    [snapshots]
+    keep = 5                      # TYB_SNAPSHOTS_KEEP, versions kept per project, 0 turns snapshots off
+
+    [logging]
+    level = "info"                # TYB_LOG_LEVEL, one of error, warn, info, debug, trace
+    max_file_mb = 10              # TYB_LOG_MAX_FILE_MB
+    max_files = 5                 # TYB_LOG_MAX_FILES, rotated files kept besides the current one
+
+    [shutdown]
+    containers = "keep"           # TYB_SHUTDOWN_CONTAINERS, "keep" or "stop"
+    deregister = true             # TYB_SHUTDOWN_DEREGISTER
+    timeout_secs = 20             # TYB_SHUTDOWN_TIMEOUT_SECS
+
+The file is given with `--config <path>` or `TYB_CONFIG`, otherwise `agent.toml` in the
+default root directory is used if it exists. Every setting is optional.
+
+Several agents can share a host as named instances. A named instance defaults to its own
+root dir (`{default root}/instances/{name}`, also where its `agent.toml` is looked up) and
+projects dir (`{default projects dir}-{name}`), and its docker images and containers get an
+instance specific suffix so no two instances see or touch each other's. Each instance also
+needs its own `server.port` and, with the tunnel enabled, its own `tunnel.web_addr`.
+*/
+
+use crate::consts::{
+    CONTAINER_MOD, DEFAULT_AGENT_ROOTDIR_PATH, DEFAULT_BIND_ADDRESS, DEFAULT_NGROK_WEB_ADDR,
+    DEFAULT_PORT, DEFAULT_SERVER_ENDPOINT, DEFAULT_UPLOAD_LIMIT_MB, IMAGE_MOD,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{env, fs, net::IpAddr, path::Path, str::FromStr, sync::OnceLock};
+use tynkerbase_universal::constants::LINUX_TYNKERBASE_PATH;
+use url::Url;
+
+const REDACTED: &str = "<redacted>";
+const MAX_INSTANCE_NAME_LEN: usize = 32;
+
+static CONFIG: OnceLock<AgentConfig> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    pub upload_limit_mb: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: DEFAULT_BIND_ADDRESS.to_string(),
+            port: DEFAULT_PORT,
+            upload_limit_mb: DEFAULT_UPLOAD_LIMIT_MB,
+        }
+    }
+}
+
+/// Which `control_plane::ControlPlane` the agent talks to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlPlaneKind {
+    #[default]
+    Http,
+    Local,
+}
+
+impl FromStr for ControlPlaneKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "http" => Ok(ControlPlaneKind::Http),
+            "local" => Ok(ControlPlaneKind::Local),
+            _ => Err(anyhow!("expected `http` or `local`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlServerConfig {
+    pub kind: ControlPlaneKind,
+    pub endpoint: String,
+}
+
+impl Default for ControlServerConfig {
+    fn default() -> Self {
+        ControlServerConfig {
+            kind: ControlPlaneKind::Http,
+            endpoint: DEFAULT_SERVER_ENDPOINT.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub root_dir: String,
+    pub projects_dir: String,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            root_dir: DEFAULT_AGENT_ROOTDIR_PATH.to_string(),
+            projects_dir: LINUX_TYNKERBASE_PATH.to_string(),
+        }
+    }
+}
+
+/// When neither path is set, a self signed certificate is generated under `{root_dir}/keys`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    pub enabled: bool,
+    pub ngrok_path: String,
+    pub authtoken: Option<String>,
+    // Address of ngrok's local API, used to look up the tunnel's public url
+    pub web_addr: String,
+    pub startup_timeout_secs: f64,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig {
+            enabled: true,
+            ngrok_path: "ngrok".to_string(),
+            authtoken: None,
+            web_addr: DEFAULT_NGROK_WEB_ADDR.to_string(),
+            startup_timeout_secs: 10.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    // Longest wait between retries while the control plane can't be reached
+    pub max_backoff_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            enabled: true,
+            interval_secs: 60,
+            max_backoff_secs: 600,
+        }
+    }
+}
+
+/// Thresholds of the checks behind `GET /health`, see `health_utils`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub min_free_disk_mb: u64,
+    pub cert_warn_days: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            min_free_disk_mb: 1024,
+            cert_warn_days: 14,
+        }
+    }
+}
+
+/// Chunked upload sessions, see `upload_utils`. Every chunk must still fit in
+/// `server.upload_limit_mb`, and finalizing an upload holds about twice its size in memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    pub max_size_mb: u64,
+    pub session_ttl_secs: u64,
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        UploadsConfig {
+            max_size_mb: 512,
+            session_ttl_secs: 60 * 60 * 24,
+        }
+    }
+}
+
+/// Earlier versions of each project, see `snapshot_utils`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotsConfig {
+    pub keep: usize,
+}
+
+impl Default for SnapshotsConfig {
+    fn default() -> Self {
+        SnapshotsConfig { keep: 5 }
+    }
+}
+
+/// The agent's own log, see `log_utils`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub max_file_mb: u64,
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            max_file_mb: 10,
+            max_files: 5,
+        }
+    }
+}
+
+/// What happens to the containers the agent manages when it shuts down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerPolicy {
+    #[default]
+    Keep,
+    Stop,
+}
+
+impl FromStr for ContainerPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(ContainerPolicy::Keep),
+            "stop" => Ok(ContainerPolicy::Stop),
+            _ => Err(anyhow!("expected `keep` or `stop`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub containers: ContainerPolicy,
+    // Tell the control server the node's public address is gone
+    pub deregister: bool,
+    pub timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            containers: ContainerPolicy::Keep,
+            deregister: true,
+            timeout_secs: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    // `None` is the default instance, which keeps the paths and names agents have always used
+    pub instance: Option<String>,
+    pub server: ServerConfig,
+    pub control_server: ControlServerConfig,
+    pub paths: PathsConfig,
+    pub tls: TlsSettings,
+    pub tunnel: TunnelConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub health: HealthConfig,
+    pub uploads: UploadsConfig,
+    pub snapshots: SnapshotsConfig,
+    pub logging: LoggingConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+fn env_override<T: FromStr>(var: &str, field: &mut T) -> Result<()> {
+    if let Ok(val) = env::var(var) {
+        *field = val
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("`{}` has an invalid value `{}`", var, val))?;
+    }
+    Ok(())
+}
+
+fn env_override_opt(var: &str, field: &mut Option<String>) {
+    if let Ok(val) = env::var(var) {
+        *field = Some(val);
+    }
+}
+
+impl AgentConfig {
+    /// Reads the config file (if any), applies environment overrides and validates the result.
+    /// `instance` (from `--instance`) takes precedence over `TYB_INSTANCE` and the file.
+    pub fn load(path: Option<&str>, instance: Option<&str>) -> Result<Self> {
+        let path = path.map(|p| p.to_string()).or_else(|| env::var("TYB_CONFIG").ok());
+        let instance = instance.map(|i| i.to_string()).or_else(|| env::var("TYB_INSTANCE").ok());
+        if let Some(name) = &instance {
+            validate_instance_name(name)?;
+        }
+
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => {
+                let default_path = format!("{}/agent.toml", default_root_dir(instance.as_deref()));
+                if Path::new(&default_path).exists() {
+                    Self::from_file(&default_path)?
+                } else {
+                    AgentConfig::default()
+                }
+            }
+        };
+
+        if instance.is_some() {
+            config.instance = instance;
+        }
+        config.apply_env()?;
+        config.apply_instance_defaults();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config file `{}` -> {}", path, e))?;
+        toml::from_str(&text).map_err(|e| anyhow!("Invalid config file `{}` -> {}", path, e))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        env_override("TYB_BIND_ADDRESS", &mut self.server.address)?;
+        env_override("TYB_PORT", &mut self.server.port)?;
+        env_override("TYB_UPLOAD_LIMIT_MB", &mut self.server.upload_limit_mb)?;
+        env_override("TYB_CONTROL_SERVER_KIND", &mut self.control_server.kind)?;
+        env_override("TYB_SERVER_ENDPOINT", &mut self.control_server.endpoint)?;
+        env_override("TYB_ROOT_DIR", &mut self.paths.root_dir)?;
+        env_override("TYB_PROJECTS_DIR", &mut self.paths.projects_dir)?;
+        env_override_opt("TYB_TLS_CERT", &mut self.tls.cert_path);
+        env_override_opt("TYB_TLS_KEY", &mut self.tls.key_path);
+        env_override("TYB_TUNNEL_ENABLED", &mut self.tunnel.enabled)?;
+        env_override("TYB_NGROK_PATH", &mut self.tunnel.ngrok_path)?;
+        env_override("TYB_NGROK_WEB_ADDR", &mut self.tunnel.web_addr)?;
+        env_override("TYB_TUNNEL_TIMEOUT_SECS", &mut self.tunnel.startup_timeout_secs)?;
+        env_override("TYB_HEARTBEAT_ENABLED", &mut self.heartbeat.enabled)?;
+        env_override("TYB_HEARTBEAT_INTERVAL_SECS", &mut self.heartbeat.interval_secs)?;
+        env_override("TYB_HEARTBEAT_MAX_BACKOFF_SECS", &mut self.heartbeat.max_backoff_secs)?;
+        env_override("TYB_HEALTH_MIN_FREE_DISK_MB", &mut self.health.min_free_disk_mb)?;
+        env_override("TYB_HEALTH_CERT_WARN_DAYS", &mut self.health.cert_warn_days)?;
+        env_override("TYB_UPLOAD_MAX_SIZE_MB", &mut self.uploads.max_size_mb)?;
+        env_override("TYB_UPLOAD_SESSION_TTL_SECS", &mut self.uploads.session_ttl_secs)?;
+        env_override("TYB_SNAPSHOTS_KEEP", &mut self.snapshots.keep)?;
+        env_override("TYB_LOG_LEVEL", &mut self.logging.level)?;
+        env_override("TYB_LOG_MAX_FILE_MB", &mut self.logging.max_file_mb)?;
+        env_override("TYB_LOG_MAX_FILES", &mut self.logging.max_files)?;
+        env_override("TYB_SHUTDOWN_CONTAINERS", &mut self.shutdown.containers)?;
+        env_override("TYB_SHUTDOWN_DEREGISTER", &mut self.shutdown.deregister)?;
+        env_override("TYB_SHUTDOWN_TIMEOUT_SECS", &mut self.shutdown.timeout_secs)?;
+        Ok(())
+    }
+
+    // Moves paths still at their defaults to the instance's own
+    fn apply_instance_defaults(&mut self) {
+        let name = match &self.instance {
+            Some(n) => n,
+            None => return,
+        };
+        if self.paths.root_dir == DEFAULT_AGENT_ROOTDIR_PATH {
+            self.paths.root_dir = default_root_dir(Some(name));
+        }
+        if self.paths.projects_dir == LINUX_TYNKERBASE_PATH {
+            self.paths.projects_dir = format!("{}-{}", LINUX_TYNKERBASE_PATH, name);
+        }
+    }
+
+    /// Checks every setting and reports all the problems at once.
+    pub fn validate(&mut self) -> Result<()> {
+        let mut errors = vec![];
+
+        if let Some(name) = &self.instance {
+            if let Err(e) = validate_instance_name(name) {
+                errors.push(e.to_string());
+            }
+            // These would collide with the default instance's
+            if self.server.port == DEFAULT_PORT {
+                errors.push(format!("instance `{}` needs its own server.port, {} is the default instance's", name, DEFAULT_PORT));
+            }
+            if self.tunnel.enabled && self.tunnel.web_addr == DEFAULT_NGROK_WEB_ADDR {
+                errors.push(format!(
+                    "instance `{}` needs its own tunnel.web_addr, {} is the default instance's",
+                    name, DEFAULT_NGROK_WEB_ADDR
+                ));
+            }
+        }
+
+        if IpAddr::from_str(&self.server.address).is_err() {
+            errors.push(format!("server.address `{}` is not an IP address", self.server.address));
+        }
+        if self.server.port == 0 {
+            errors.push("server.port must not be 0".to_string());
+        }
+        if self.server.upload_limit_mb == 0 {
+            errors.push("server.upload_limit_mb must be greater than 0".to_string());
+        }
+
+        match Url::parse(&self.control_server.endpoint) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                let trimmed = self.control_server.endpoint.trim_end_matches('/').to_string();
+                self.control_server.endpoint = trimmed;
+            }
+            _ => errors.push(format!(
+                "control_server.endpoint `{}` is not an http(s) url",
+                self.control_server.endpoint
+            )),
+        }
+
+        if self.paths.root_dir.trim().is_empty() {
+            errors.push("paths.root_dir must not be empty".to_string());
+        }
+        if self.paths.projects_dir.trim().is_empty() {
+            errors.push("paths.projects_dir must not be empty".to_string());
+        }
+
+        match (&self.tls.cert_path, &self.tls.key_path) {
+            (None, None) => {}
+            (Some(cert), Some(key)) => {
+                for path in [cert, key] {
+                    if !Path::new(path).exists() {
+                        errors.push(format!("TLS file `{}` does not exist", path));
+                    }
+                }
+            }
+            _ => errors.push("tls.cert_path and tls.key_path must be set together".to_string()),
+        }
+
+        if self.tunnel.ngrok_path.trim().is_empty() {
+            errors.push("tunnel.ngrok_path must not be empty".to_string());
+        }
+        if self.tunnel.web_addr.trim().is_empty() {
+            errors.push("tunnel.web_addr must not be empty".to_string());
+        }
+        if !(self.tunnel.startup_timeout_secs.is_finite() && self.tunnel.startup_timeout_secs > 0.) {
+            errors.push("tunnel.startup_timeout_secs must be greater than 0".to_string());
+        }
+        if self.heartbeat.interval_secs == 0 {
+            errors.push("heartbeat.interval_secs must be greater than 0".to_string());
+        }
+        if self.heartbeat.max_backoff_secs == 0 {
+            errors.push("heartbeat.max_backoff_secs must be greater than 0".to_string());
+        }
+        if self.uploads.max_size_mb == 0 {
+            errors.push("uploads.max_size_mb must be greater than 0".to_string());
+        }
+        if self.uploads.session_ttl_secs == 0 {
+            errors.push("uploads.session_ttl_secs must be greater than 0".to_string());
+        }
+        if log::LevelFilter::from_str(&self.logging.level).is_err() {
+            errors.push(format!(
+                "logging.level `{}` must be one of off, error, warn, info, debug or trace",
+                self.logging.level
+            ));
+        }
+        if self.logging.max_file_mb == 0 {
+            errors.push("logging.max_file_mb must be greater than 0".to_string());
+        }
+        if self.shutdown.timeout_secs == 0 {
+            errors.push("shutdown.timeout_secs must be greater than 0".to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!("Invalid configuration:\n  - {}", errors.join("\n  - ")));
+        }
+        Ok(())
+    }
+
+    /// The config as TOML, with secrets (the ngrok token, credentials in urls) hidden.
+    pub fn to_redacted_string(&self) -> String {
+        let mut config = self.clone();
+        if config.tunnel.authtoken.is_some() {
+            config.tunnel.authtoken = Some(REDACTED.to_string());
+        }
+        if let Ok(mut url) = Url::parse(&config.control_server.endpoint) {
+            if url.password().is_some() {
+                let _ = url.set_password(Some(REDACTED));
+                config.control_server.endpoint = url.to_string();
+            }
+        }
+        toml::to_string_pretty(&config).unwrap_or_else(|e| format!("Failed to render config -> {e}"))
+    }
+}
+
+/// Makes `config` the config used for the rest of the process. Can only be called once.
+pub fn init(config: AgentConfig) {
+    if CONFIG.set(config).is_err() {
+        panic!("config_utils::init called twice");
+    }
+}
+
+pub fn get() -> &'static AgentConfig {
+    CONFIG.get().expect("config not loaded, call config_utils::init first")
+}
+
+fn default_root_dir(instance: Option<&str>) -> String {
+    match instance {
+        Some(name) => format!("{}/instances/{}", DEFAULT_AGENT_ROOTDIR_PATH, name),
+        None => DEFAULT_AGENT_ROOTDIR_PATH.to_string(),
+    }
+}
+
+/// Instance names end up in paths and docker names, so they are kept to lowercase letters,
+/// digits and single `-`s.
+pub fn validate_instance_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_INSTANCE_NAME_LEN {
+        return Err(anyhow!("Instance name must be 1 to {} characters long", MAX_INSTANCE_NAME_LEN));
+    }
+    if !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        return Err(anyhow!(
+            "Instance name `{}` may only contain lowercase letters, digits and `-`",
+            name
+        ));
+    }
+    if name.starts_with('-') || name.ends_with('-') || name.contains("--") {
+        return Err(anyhow!("Instance name `{}` must not start, end or repeat `-`", name));
+    }
+    Ok(())
+}
+
+/// The name of this instance, `None` for the default one.
+pub fn instance() -> Option<&'static str> {
+    get().instance.as_deref()
+}
+
+/// Appended to project names to get this instance's container names.
+pub fn container_suffix() -> String {
+    match instance() {
+        Some(name) => format!("__tyb_{name}_container"),
+        None => CONTAINER_MOD.to_string(),
+    }
+}
+
+/// Appended to project names to get this instance's image names.
+pub fn image_suffix() -> String {
+    match instance() {
+        Some(name) => format!("__tyb_{name}_image"),
+        None => IMAGE_MOD.to_string(),
+    }
+}
+
+pub fn root_dir() -> &'static str {
+    &get().paths.root_dir
+}
+
+pub fn projects_dir() -> &'static str {
+    &get().paths.projects_dir
+}
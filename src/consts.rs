@@ -10,7 +10,14 @@ const fn get_proj_path() -> &'static str {
     "/usr/share/tynkerbase-agent"
 }
 
-pub const AGENT_ROOTDIR_PATH: &str = get_proj_path();
-pub const SERVER_ENDPOINT: &str = "https://tynkerbase-server.shuttleapp.rs";
+// Defaults for settings that can be changed in the config file (see `config_utils`)
+pub const DEFAULT_AGENT_ROOTDIR_PATH: &str = get_proj_path();
+pub const DEFAULT_SERVER_ENDPOINT: &str = "https://tynkerbase-server.shuttleapp.rs";
+pub const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0";
+pub const DEFAULT_PORT: u16 = 7462;
+pub const DEFAULT_UPLOAD_LIMIT_MB: u64 = 20;
+pub const DEFAULT_NGROK_WEB_ADDR: &str = "localhost:4040";
+
+// Suffixes of the default instance's docker names, named instances use `config_utils::container_suffix` etc.
 pub const CONTAINER_MOD: &str = "__tyb_container";
-pub const IMAGE_MOD: &str = "__tyb_image";
\ No newline at end of file
+pub const IMAGE_MOD: &str = "__tyb_image";
@@ -0,0 +1,418 @@
+/*
+Everything the agent asks of the control server goes through `ControlPlane`.
+
+`HttpControlPlane` talks to the TynkerBase control server, or a self-hosted one at
+`control_server.endpoint`. `LocalControlPlane` answers in process, keeping accounts, ngrok
+tokens and node addresses in memory (and optionally in a file), so the agent can be run and
+its startup tested without any network access. `control_server.kind` picks which one is used.
+*/
+
+use crate::{
+    auth_utils,
+    config_utils::{self, ControlPlaneKind},
+    heartbeat_utils::Heartbeat,
+};
+use anyhow::{anyhow, Result};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+use tynkerbase_universal::netwk_utils::Node;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+const SALT_LEN: usize = 32;
+
+static CONTROL_PLANE: OnceLock<Box<dyn ControlPlane>> = OnceLock::new();
+
+pub enum LoginResult {
+    // The salt the API key is derived from
+    Success(String),
+    Unauthorized,
+    Unreachable(anyhow::Error),
+}
+
+#[rocket::async_trait]
+pub trait ControlPlane: Send + Sync {
+    /// Where the control plane lives, for messages.
+    fn describe(&self) -> String;
+
+    /// Checks that the control plane can be reached (regardless of what it responds with).
+    async fn ping(&self) -> Result<()>;
+
+    async fn login(&self, email: &str, pass_sha256: &str) -> LoginResult;
+
+    /// Replaces the salt the API key is derived from and returns the new salt.
+    async fn rotate_salt(&self, email: &str, pass_sha256: &str) -> Result<String>;
+
+    /// Whether another node of the account already uses `name`.
+    async fn node_name_taken(&self, email: &str, pass_sha256: &str, name: &str) -> Result<bool>;
+
+    /// Stores the (already encrypted) ngrok token of the account.
+    async fn save_ngrok_token(&self, email: &str, pass_sha256: &str, token: Vec<u8>) -> Result<()>;
+
+    async fn get_ngrok_token(&self, email: &str, pass_sha256: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Publishes the node's public address.
+    async fn add_node_addr(&self, email: &str, pass_sha256: &str, node: &Node) -> Result<()>;
+
+    /// Withdraws the node's public address so clients stop trying to reach it.
+    async fn remove_node_addr(&self, email: &str, pass_sha256: &str, node: &Node) -> Result<()>;
+
+    /// Reports that the node is alive, see `heartbeat_utils`.
+    async fn heartbeat(&self, email: &str, pass_sha256: &str, beat: &Heartbeat) -> Result<()>;
+}
+
+/// Makes `plane` the control plane used for the rest of the process. Can only be called once.
+pub fn init(plane: Box<dyn ControlPlane>) {
+    if CONTROL_PLANE.set(plane).is_err() {
+        panic!("control_plane::init called twice");
+    }
+}
+
+pub fn get() -> &'static dyn ControlPlane {
+    CONTROL_PLANE
+        .get()
+        .expect("control plane not set, call control_plane::init first")
+        .as_ref()
+}
+
+/// Builds the control plane selected in the config.
+pub fn from_config() -> Result<Box<dyn ControlPlane>> {
+    let config = config_utils::get();
+    Ok(match config.control_server.kind {
+        ControlPlaneKind::Http => Box::new(HttpControlPlane::new(&config.control_server.endpoint)),
+        ControlPlaneKind::Local => {
+            let path = format!("{}/data/control-plane.json", config_utils::root_dir());
+            Box::new(LocalControlPlane::open(&path)?)
+        }
+    })
+}
+
+pub struct HttpControlPlane {
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl HttpControlPlane {
+    pub fn new(endpoint: &str) -> Self {
+        HttpControlPlane {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // Builds a request carrying the account's credentials, which the server takes as query params
+    fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        email: &str,
+        pass_sha256: &str,
+    ) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.endpoint, path))
+            .query(&[("email", email), ("pass_sha256", pass_sha256)])
+            .timeout(REQUEST_TIMEOUT)
+    }
+}
+
+async fn send(req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    let res = req.send().await.map_err(|e| anyhow!("Error sending req -> {}", e))?;
+    if !res.status().is_success() {
+        return Err(anyhow!("Server responded with {}", res.status()));
+    }
+    Ok(res)
+}
+
+async fn send_for_text(req: reqwest::RequestBuilder) -> Result<String> {
+    send(req)
+        .await?
+        .text()
+        .await
+        .map_err(|e| anyhow!("Unable to extract text from response -> {}", e))
+}
+
+#[rocket::async_trait]
+impl ControlPlane for HttpControlPlane {
+    fn describe(&self) -> String {
+        self.endpoint.clone()
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.client
+            .get(&self.endpoint)
+            .timeout(PING_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Error sending req -> {}", e))?;
+        Ok(())
+    }
+
+    async fn login(&self, email: &str, pass_sha256: &str) -> LoginResult {
+        let req = self.request(reqwest::Method::GET, "/auth/login", email, pass_sha256);
+        let res = match req.send().await {
+            Ok(r) => r,
+            Err(e) => return LoginResult::Unreachable(anyhow!("Error sending req -> {}", e)),
+        };
+
+        if res.status().as_u16() == 403 {
+            return LoginResult::Unauthorized;
+        }
+        if !res.status().is_success() {
+            return LoginResult::Unreachable(anyhow!("Server responded with {}", res.status()));
+        }
+
+        match res.text().await {
+            Ok(salt) => LoginResult::Success(salt),
+            Err(e) => LoginResult::Unreachable(anyhow!("Unable to extract text from response -> {}", e)),
+        }
+    }
+
+    async fn rotate_salt(&self, email: &str, pass_sha256: &str) -> Result<String> {
+        send_for_text(self.request(reqwest::Method::GET, "/auth/rotate-salt", email, pass_sha256)).await
+    }
+
+    async fn node_name_taken(&self, email: &str, pass_sha256: &str, name: &str) -> Result<bool> {
+        let req = self
+            .request(reqwest::Method::GET, "/ngrok/check-node-exists/name", email, pass_sha256)
+            .query(&[("name", name)]);
+        let text = send_for_text(req)
+            .await
+            .map_err(|e| anyhow!("Error communicating with database -> {}", e))?;
+        Ok(text != "false")
+    }
+
+    async fn save_ngrok_token(&self, email: &str, pass_sha256: &str, token: Vec<u8>) -> Result<()> {
+        let req = self
+            .request(reqwest::Method::POST, "/ngrok/save-ng-auth", email, pass_sha256)
+            .body(token);
+        send(req).await?;
+        Ok(())
+    }
+
+    async fn get_ngrok_token(&self, email: &str, pass_sha256: &str) -> Result<Option<Vec<u8>>> {
+        let req = self
+            .request(reqwest::Method::GET, "/ngrok/get-ng-auth", email, pass_sha256)
+            .timeout(PING_TIMEOUT);
+        let body = send(req)
+            .await?
+            .bytes()
+            .await
+            .map_err(|e| anyhow!("Error getting bytes from response -> {}", e))?;
+        Ok(Some(body.to_vec()))
+    }
+
+    async fn add_node_addr(&self, email: &str, pass_sha256: &str, node: &Node) -> Result<()> {
+        let bin = bincode::serialize(node).map_err(|e| anyhow!("Failed to serialize node -> {}", e))?;
+        let req = self
+            .request(reqwest::Method::POST, "/ngrok/add-addr", email, pass_sha256)
+            .body(bin);
+        send(req).await?;
+        Ok(())
+    }
+
+    async fn remove_node_addr(&self, email: &str, pass_sha256: &str, node: &Node) -> Result<()> {
+        let bin = bincode::serialize(node).map_err(|e| anyhow!("Failed to serialize node -> {}", e))?;
+        let req = self
+            .request(reqwest::Method::POST, "/ngrok/remove-addr", email, pass_sha256)
+            .body(bin)
+            .timeout(PING_TIMEOUT);
+        send(req).await?;
+        Ok(())
+    }
+
+    async fn heartbeat(&self, email: &str, pass_sha256: &str, beat: &Heartbeat) -> Result<()> {
+        let json = serde_json::to_vec(beat).map_err(|e| anyhow!("Failed to serialize heartbeat -> {}", e))?;
+        let req = self
+            .request(reqwest::Method::POST, "/node/heartbeat", email, pass_sha256)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json);
+        send(req).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LocalNode {
+    name: String,
+    addr: String,
+    #[serde(default)]
+    last_heartbeat: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LocalAccount {
+    pass_sha256: String,
+    salt: String,
+    ngrok_token: Option<Vec<u8>>,
+    // Keyed by node id
+    nodes: HashMap<String, LocalNode>,
+}
+
+/// An in-process control plane. The first login with an email creates the account, later
+/// ones must use the same password.
+#[derive(Debug, Default)]
+pub struct LocalControlPlane {
+    accounts: Mutex<HashMap<String, LocalAccount>>,
+    // Where the accounts are persisted, `None` keeps them in memory only
+    path: Option<String>,
+}
+
+fn gen_salt() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SALT_LEN)
+        .map(char::from)
+        .collect()
+}
+
+impl LocalControlPlane {
+    /// A control plane persisted to `path`, so salts (and with them API keys) survive restarts.
+    pub fn open(path: &str) -> Result<Self> {
+        let accounts = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| anyhow!("Local control plane state `{path}` is corrupted -> {e}"))?,
+            Err(_) => HashMap::new(),
+        };
+        Ok(LocalControlPlane {
+            accounts: Mutex::new(accounts),
+            path: Some(path.to_string()),
+        })
+    }
+
+    fn read_account<T>(&self, email: &str, pass_sha256: &str, f: impl FnOnce(&LocalAccount) -> T) -> Result<T> {
+        self.access_account(email, pass_sha256, false, |account| f(account))
+    }
+
+    fn update_account<T>(
+        &self,
+        email: &str,
+        pass_sha256: &str,
+        f: impl FnOnce(&mut LocalAccount) -> T,
+    ) -> Result<T> {
+        self.access_account(email, pass_sha256, true, f)
+    }
+
+    // Runs `f` on the account if the credentials match
+    fn access_account<T>(
+        &self,
+        email: &str,
+        pass_sha256: &str,
+        save: bool,
+        f: impl FnOnce(&mut LocalAccount) -> T,
+    ) -> Result<T> {
+        let mut accounts = self
+            .accounts
+            .lock()
+            .map_err(|e| anyhow!("Local control plane lock poisoned -> {}", e))?;
+        let account = accounts
+            .get_mut(email)
+            .filter(|a| a.pass_sha256 == pass_sha256)
+            .ok_or_else(|| anyhow!("Incorrect authorization"))?;
+        let res = f(account);
+        if save {
+            self.save(&accounts)?;
+        }
+        Ok(res)
+    }
+
+    fn save(&self, accounts: &HashMap<String, LocalAccount>) -> Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir).map_err(|e| anyhow!("Failed to create `{}` -> {e}", dir.display()))?;
+        }
+        let json = serde_json::to_string_pretty(accounts)
+            .map_err(|e| anyhow!("Failed to serialize local control plane state -> {e}"))?;
+        fs::write(path, json).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))
+    }
+}
+
+#[rocket::async_trait]
+impl ControlPlane for LocalControlPlane {
+    fn describe(&self) -> String {
+        match &self.path {
+            Some(path) => format!("local ({path})"),
+            None => "local (in memory)".to_string(),
+        }
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn login(&self, email: &str, pass_sha256: &str) -> LoginResult {
+        let mut accounts = match self.accounts.lock() {
+            Ok(a) => a,
+            Err(e) => return LoginResult::Unreachable(anyhow!("Local control plane lock poisoned -> {}", e)),
+        };
+        let account = accounts.entry(email.to_string()).or_insert_with(|| LocalAccount {
+            pass_sha256: pass_sha256.to_string(),
+            salt: gen_salt(),
+            ..Default::default()
+        });
+        if account.pass_sha256 != pass_sha256 {
+            return LoginResult::Unauthorized;
+        }
+        let salt = account.salt.clone();
+        if let Err(e) = self.save(&accounts) {
+            return LoginResult::Unreachable(e);
+        }
+        LoginResult::Success(salt)
+    }
+
+    async fn rotate_salt(&self, email: &str, pass_sha256: &str) -> Result<String> {
+        self.update_account(email, pass_sha256, |account| {
+            account.salt = gen_salt();
+            account.salt.clone()
+        })
+    }
+
+    async fn node_name_taken(&self, email: &str, pass_sha256: &str, name: &str) -> Result<bool> {
+        self.read_account(email, pass_sha256, |account| {
+            account.nodes.values().any(|n| n.name == name)
+        })
+    }
+
+    async fn save_ngrok_token(&self, email: &str, pass_sha256: &str, token: Vec<u8>) -> Result<()> {
+        self.update_account(email, pass_sha256, |account| account.ngrok_token = Some(token))
+    }
+
+    async fn get_ngrok_token(&self, email: &str, pass_sha256: &str) -> Result<Option<Vec<u8>>> {
+        self.read_account(email, pass_sha256, |account| account.ngrok_token.clone())
+    }
+
+    async fn add_node_addr(&self, email: &str, pass_sha256: &str, node: &Node) -> Result<()> {
+        self.update_account(email, pass_sha256, |account| {
+            let local = account.nodes.entry(node.node_id.clone()).or_default();
+            local.name = node.name.clone();
+            local.addr = node.addr.clone();
+        })
+    }
+
+    async fn remove_node_addr(&self, email: &str, pass_sha256: &str, node: &Node) -> Result<()> {
+        self.update_account(email, pass_sha256, |account| {
+            // Keep the node so its name stays taken
+            if let Some(n) = account.nodes.get_mut(&node.node_id) {
+                n.addr.clear();
+            }
+        })
+    }
+
+    async fn heartbeat(&self, email: &str, pass_sha256: &str, beat: &Heartbeat) -> Result<()> {
+        self.update_account(email, pass_sha256, |account| {
+            let node = account.nodes.entry(beat.node_id.clone()).or_default();
+            node.name = beat.name.clone();
+            node.addr = beat.public_addr.clone().unwrap_or_default();
+            node.last_heartbeat = Some(auth_utils::now_secs());
+        })
+    }
+}
@@ -13,7 +13,7 @@ pub fn find_package_manager() -> Result<&'static str> {
             .arg(pm)
             .output()
             .ok()
-            .map_or(false, |output| output.status.success())
+            .is_some_and(|output| output.status.success())
         {
             return Ok(pm);
         }
@@ -68,6 +68,7 @@ pub mod docker {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn get_install_command() -> Result<Vec<&'static str>> {
         let package_manager = find_package_manager()?;
         match package_manager {
@@ -89,13 +90,7 @@ pub mod openssl {
     pub fn check_openssl() -> bool {
         // Checks if openssl is installed by running `openssl version`
         match Command::new("openssl").arg("version").output() {
-            Ok(output) => {
-                if output.status.success() {
-                    true
-                } else {
-                    false
-                }
-            }
+            Ok(output) => output.status.success(),
             Err(_e) => false,
         }
     }
@@ -128,6 +123,7 @@ pub mod openssl {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn get_install_command() -> Result<Vec<&'static str>> {
         let pm = find_package_manager()?;
         match pm {
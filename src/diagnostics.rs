@@ -25,18 +25,11 @@ async fn get_manufacturer(diags: Arc<Mutex<NodeDiags>>) {
         .arg("dmidecode -s system-manufacturer")
         .output();
 
-    match output.await {
-        Ok(o) => {
-            let s = String::from_utf8(o.stdout);
-            match s {
-                Ok(s) => {
-                    let mut lock = diags.lock().unwrap();
-                    lock.manufacturer = Some(s);
-                },
-                _ => {},
-            }
+    if let Ok(o) = output.await {
+        if let Ok(s) = String::from_utf8(o.stdout) {
+            let mut lock = diags.lock().unwrap();
+            lock.manufacturer = Some(s);
         }
-        _ => {},
     }
 }
 
@@ -1,5 +1,6 @@
+use crate::config_utils;
 use anyhow::{anyhow, Result};
-use std::env::consts::OS;
+use std::{collections::HashMap, env::consts::OS};
 use tokio::process::Command;
 
 pub async fn start_daemon() -> Result<()> {
@@ -73,7 +74,7 @@ pub async fn get_engine_status() -> Result<bool> {
 
         return Err(anyhow!("Error parsing `systemctl status docker` output"));
     }
-    return Err(anyhow!("OS `{}` not supported.", OS));
+    Err(anyhow!("OS `{}` not supported.", OS))
 }
 
 pub async fn build_image(path: &str, img_name: &str) -> Result<()> {
@@ -91,10 +92,29 @@ pub async fn build_image(path: &str, img_name: &str) -> Result<()> {
     Ok(())
 }
 
+/// The id (`sha256:...`) of a built image.
+pub async fn image_id(img_name: &str) -> Result<String> {
+    let output = Command::new("docker")
+        .args(["image", "inspect", "--format", "{{.Id}}", img_name])
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to launch docker command -> {e}"))?;
+
+    if !output.status.success() {
+        let err = String::from_utf8(output.stderr)
+            .unwrap_or("Unable to extract stderr".to_string());
+        return Err(anyhow!("Failed to inspect image `{}`:\n{}", img_name, err));
+    }
+
+    let out = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("Error extracting stdout -> {}", e))?;
+    Ok(out.trim().to_string())
+}
+
 pub async fn delete_image(img_name: impl AsRef<str>) -> Result<()> {
     let img_name = img_name.as_ref();
     let output = Command::new("docker")
-        .args(&["rmi", "-f", img_name])
+        .args(["rmi", "-f", img_name])
         .output()
         .await
         .map_err(|e| anyhow!("Failed to launch docker command -> {e}"))?;
@@ -108,10 +128,15 @@ pub async fn delete_image(img_name: impl AsRef<str>) -> Result<()> {
     Ok(())
 }
 
+// Header `list_container_stats` returns when this instance has no running containers
+const STATS_HEADER: &str = "CONTAINER ID|||NAME|||CPU %|||MEM USAGE / LIMIT|||MEM %|||NET I/O|||BLOCK I/O|||PIDS";
+
+/// Images built by this instance.
 pub async fn list_images() -> Result<String> {
     // TODO: Test this function
+    let filter = format!("reference=*{}", config_utils::image_suffix());
     let cmd = Command::new("docker")
-        .arg("images")
+        .args(["images", "--filter", &filter])
         .output()
         .await
         .map_err(|e| anyhow!("{}", e))?;
@@ -121,9 +146,11 @@ pub async fn list_images() -> Result<String> {
     Ok(s)
 }
 
+/// Containers (running or not) spawned by this instance.
 pub async fn list_containers() -> Result<String> {
+    let filter = format!("name={}$", config_utils::container_suffix());
     let output = Command::new("docker")
-        .args(["ps", "-a", "--format", "table {{.ID}}|||{{.Image}}|||{{.Command}}|||{{.CreatedAt}}|||{{.Status}}|||{{.Ports}}|||{{.Names}}"])
+        .args(["ps", "-a", "--filter", &filter, "--format", "table {{.ID}}|||{{.Image}}|||{{.Command}}|||{{.CreatedAt}}|||{{.Status}}|||{{.Ports}}|||{{.Names}}"])
         .output()
         .await
         .map_err(|e| anyhow!("Error executing list_containers docker command [fn list_containers] -> {}", e))?;
@@ -133,9 +160,17 @@ pub async fn list_containers() -> Result<String> {
         .map_err(|e| anyhow!("Error extracting stdout [fn list_containers] -> {}", e))
 }
 
+/// Resource usage of this instance's running containers.
 pub async fn list_container_stats() -> Result<String> {
+    // Without names `docker stats` reports every container on the host
+    let containers = list_running_managed_containers().await?;
+    if containers.is_empty() {
+        return Ok(format!("{STATS_HEADER}\n"));
+    }
+
     let output = Command::new("docker")
         .args(["stats", "--no-stream", "--format", "table {{.ID}}|||{{.Container}}|||{{.CPUPerc}}|||{{.MemUsage}}|||{{.MemPerc}}|||{{.NetIO}}|||{{.BlockIO}}|||{{.PIDs}}"])
+        .args(&containers)
         .output()
         .await
         .map_err(|e| anyhow!("Error executing `docker stats` command [fn list_container_stats] => {}", e))?;
@@ -158,6 +193,8 @@ pub async fn start_container(
     container_name: &str,
     ports: &Vec<[u16; 2]>,
     volumes: &Vec<[String; 2]>,
+    env_file: Option<&str>,
+    ro_volumes: &[[String; 2]],
 ) -> Result<()> {
     let mut args = vec![
         "run".to_string(),
@@ -176,6 +213,16 @@ pub async fn start_container(
         args.push(format!("{}:{}", &v[0], &v[1]));
     }
 
+    for v in ro_volumes {
+        args.push("-v".to_string());
+        args.push(format!("{}:{}:ro", &v[0], &v[1]));
+    }
+
+    if let Some(path) = env_file {
+        args.push("--env-file".to_string());
+        args.push(path.to_string());
+    }
+
     args.push(img_name.to_string());
 
     let cmd = Command::new("docker")
@@ -196,9 +243,82 @@ pub async fn start_container(
     Ok(())
 }
 
+/// How many of this instance's containers are running, and how many there are in total.
+pub async fn count_containers() -> Result<(usize, usize)> {
+    let filter = format!("name={}$", config_utils::container_suffix());
+    let output = Command::new("docker")
+        .args(["ps", "-a", "--filter", &filter, "--format", "{{.State}}"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to launch docker command -> {e}"))?;
+
+    if !output.status.success() {
+        let err = String::from_utf8(output.stderr)
+            .unwrap_or("Unable to extract stderr".to_string());
+        return Err(anyhow!("Failed to list containers:\n{}", err));
+    }
+
+    let out = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("Error extracting stdout -> {}", e))?;
+    let states: Vec<&str> = out.lines().filter(|l| !l.is_empty()).collect();
+    let running = states.iter().filter(|s| **s == "running").count();
+    Ok((running, states.len()))
+}
+
+/// The state (`running`, `exited`, ...) of each of this instance's containers, by container name.
+pub async fn container_states() -> Result<HashMap<String, String>> {
+    let suffix = config_utils::container_suffix();
+    let filter = format!("name={suffix}$");
+    let output = Command::new("docker")
+        .args(["ps", "-a", "--filter", &filter, "--format", "{{.Names}}|||{{.State}}"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to launch docker command -> {e}"))?;
+
+    if !output.status.success() {
+        let err = String::from_utf8(output.stderr)
+            .unwrap_or("Unable to extract stderr".to_string());
+        return Err(anyhow!("Failed to list containers:\n{}", err));
+    }
+
+    let out = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("Error extracting stdout -> {}", e))?;
+    Ok(out
+        .lines()
+        .filter_map(|l| l.split_once("|||"))
+        .filter(|(name, _)| name.ends_with(&suffix))
+        .map(|(name, state)| (name.to_string(), state.to_string()))
+        .collect())
+}
+
+/// Names of the running containers spawned by this instance.
+pub async fn list_running_managed_containers() -> Result<Vec<String>> {
+    let suffix = config_utils::container_suffix();
+    let filter = format!("name={suffix}$");
+    let output = Command::new("docker")
+        .args(["ps", "--filter", &filter, "--format", "{{.Names}}"])
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to launch docker command -> {e}"))?;
+
+    if !output.status.success() {
+        let err = String::from_utf8(output.stderr)
+            .unwrap_or("Unable to extract stderr".to_string());
+        return Err(anyhow!("Failed to list containers:\n{}", err));
+    }
+
+    let out = String::from_utf8(output.stdout)
+        .map_err(|e| anyhow!("Error extracting stdout -> {}", e))?;
+    Ok(out
+        .lines()
+        .filter(|l| l.ends_with(&suffix))
+        .map(|l| l.to_string())
+        .collect())
+}
+
 pub async fn pause_container(container_name: &str) -> Result<()> {
     let output = Command::new("docker")
-        .args(&["stop", container_name])
+        .args(["stop", container_name])
         .output()
         .await
         .map_err(|e| anyhow!("Failed to launch docker command -> {e}"))?;
@@ -215,7 +335,7 @@ pub async fn pause_container(container_name: &str) -> Result<()> {
 pub async fn delete_container(container_name: impl AsRef<str>) -> Result<()>{
     let container_name = container_name.as_ref();
     let output = Command::new("docker")
-        .args(&["rm", "-f", container_name])
+        .args(["rm", "-f", container_name])
         .output()
         .await
         .map_err(|e| anyhow!("Failed to launch docker command -> {e}"))?;
@@ -224,7 +344,7 @@ pub async fn delete_container(container_name: impl AsRef<str>) -> Result<()>{
         let err = String::from_utf8(output.stderr)
             .unwrap_or("Unable to extract stderr".to_string());
         let err = anyhow!("Failed to delete container `{}`:\n{}", container_name, err);
-        #[cfg(debug_assertions)] println!("{:#?}", err);
+        log::debug!("{}", err);
         return Err(err);
     }
 
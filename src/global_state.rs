@@ -1,4 +1,9 @@
-use std::sync::Arc;
+use crate::{
+    auth_utils::{ClientCert, ScopedToken},
+    ban_utils::BanTracker,
+    signing_utils::NonceCache,
+};
+use std::{sync::Arc, time::Instant};
 use tokio::sync::RwLock;
 
 #[derive(Debug, Default)]
@@ -9,6 +14,16 @@ pub struct GlobalState {
     pub pass_sha256: Option<String>,
     pub pass_sha384: Option<String>,
     pub tyb_apikey: Option<String>,
+    // Key replaced by the last rotation, accepted until the grace period ends
+    pub prev_apikey: Option<(String, Instant)>,
+    pub session_key: Option<String>,
+    pub public_addr: Option<String>,
+    // Set while the agent is running from its cached session because the control server is down
+    pub offline: bool,
+    pub tokens: Vec<ScopedToken>,
+    pub client_certs: Vec<ClientCert>,
+    pub bans: BanTracker,
+    pub nonces: NonceCache,
 }
 
 pub type TsGlobalState = Arc<RwLock<GlobalState>>;
@@ -18,6 +33,20 @@ impl GlobalState {
         Arc::new(RwLock::new(Self::default()))
     }
 
+    /// The current API key followed by the previous one if it's still within its grace period.
+    pub fn valid_apikeys(&self) -> Vec<&str> {
+        let mut keys = vec![];
+        if let Some(key) = &self.tyb_apikey {
+            keys.push(key.as_str());
+        }
+        if let Some((key, expires)) = &self.prev_apikey {
+            if *expires > Instant::now() {
+                keys.push(key.as_str());
+            }
+        }
+        keys
+    }
+
     pub fn check_status(&self) -> bool {
         self.node_id.is_some()
             && self.name.is_some()
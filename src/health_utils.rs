@@ -0,0 +1,221 @@
+/*
+Readiness checks behind `GET /health`. Every check reports `ok`, `warn` or `fail` with a short
+message, and the worst of them is the overall status:
+
+    ok      200     everything works
+    warn    200     the node serves requests but needs attention (e.g. cert expires soon)
+    fail    503     the node can't do its job (e.g. docker is down, the disk is full)
+
+The endpoint is meant for load balancers and monitors, so it doesn't require an API key and
+the messages avoid paths, addresses and raw errors. Its report is reused for `CACHE_TTL` so
+it can't be used to hammer docker, and it leaves out the control server check, which would let
+anyone make the agent send requests to the control server. `GET /health/details` requires an
+API key and runs every check, the control server's included.
+*/
+
+use crate::{config_utils, control_plane, docker_utils, global_state::TsGlobalState, ngrok_utils, tls_utils};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+use tokio::{process::Command, sync::Mutex};
+
+// No single check may hold up the response for longer than this
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const CACHE_TTL: Duration = Duration::from_secs(5);
+const SECS_PER_DAY: u64 = 60 * 60 * 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: HealthStatus,
+    pub message: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: Vec<CheckResult>,
+}
+
+impl HealthReport {
+    pub fn http_status(&self) -> u16 {
+        match self.status {
+            HealthStatus::Ok | HealthStatus::Warn => 200,
+            HealthStatus::Fail => 503,
+        }
+    }
+}
+
+async fn run_check<F>(name: &'static str, check: F) -> CheckResult
+where
+    F: Future<Output = (HealthStatus, String)>,
+{
+    let start = Instant::now();
+    let (status, message) = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(r) => r,
+        Err(_) => (HealthStatus::Fail, format!("timed out after {:?}", CHECK_TIMEOUT)),
+    };
+    CheckResult {
+        name,
+        status,
+        message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+async fn check_docker() -> (HealthStatus, String) {
+    match docker_utils::get_engine_status().await {
+        Ok(true) => (HealthStatus::Ok, "running".to_string()),
+        Ok(false) => (HealthStatus::Fail, "not running".to_string()),
+        Err(e) => {
+            log::warn!("Health check couldn't get the docker status -> {}", e);
+            (HealthStatus::Fail, "unable to get status".to_string())
+        }
+    }
+}
+
+/// Free space (in MB) on the filesystem holding `path`.
+pub async fn free_disk_mb(path: &str) -> Result<u64> {
+    let output = Command::new("df")
+        .args(["-Pk", path])
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run df -> {e}"))?;
+    if !output.status.success() {
+        let err = String::from_utf8(output.stderr).unwrap_or("Unable to extract stderr".to_string());
+        return Err(anyhow!("df failed -> {err}"));
+    }
+
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let out = String::from_utf8(output.stdout).map_err(|e| anyhow!("Error extracting stdout -> {e}"))?;
+    let available_kb: u64 = out
+        .lines()
+        .nth(1)
+        .and_then(|l| l.split_whitespace().nth(3))
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| anyhow!("Unexpected df output `{}`", out.trim()))?;
+    Ok(available_kb / 1024)
+}
+
+async fn check_disk() -> (HealthStatus, String) {
+    let min_mb = config_utils::get().health.min_free_disk_mb;
+    match free_disk_mb(config_utils::projects_dir()).await {
+        Ok(free) if free < min_mb => (HealthStatus::Fail, format!("{free} MB free, below the {min_mb} MB minimum")),
+        Ok(free) if free < min_mb * 2 => (HealthStatus::Warn, format!("{free} MB free, close to the {min_mb} MB minimum")),
+        Ok(free) => (HealthStatus::Ok, format!("{free} MB free")),
+        Err(e) => {
+            log::warn!("Health check couldn't get the free disk space -> {}", e);
+            (HealthStatus::Fail, "unable to get free space".to_string())
+        }
+    }
+}
+
+async fn check_tunnel(public: bool) -> (HealthStatus, String) {
+    if !public {
+        return (HealthStatus::Ok, "not used, the agent is private".to_string());
+    }
+    match ngrok_utils::tunnel_running() {
+        true => (HealthStatus::Ok, "running".to_string()),
+        false => (HealthStatus::Fail, "the ngrok process has exited".to_string()),
+    }
+}
+
+async fn check_cert() -> (HealthStatus, String) {
+    let path = tls_utils::get_cert_paths()[0].clone();
+    let warn_days = config_utils::get().health.cert_warn_days;
+
+    let res = tokio::task::spawn_blocking(move || -> Result<(HealthStatus, String)> {
+        if tls_utils::cert_expires_within(&path, 0)? {
+            return Ok((HealthStatus::Fail, "expired".to_string()));
+        }
+        if tls_utils::cert_expires_within(&path, warn_days * SECS_PER_DAY)? {
+            return Ok((HealthStatus::Warn, format!("expires within {warn_days} days")));
+        }
+        Ok((HealthStatus::Ok, format!("valid for more than {warn_days} days")))
+    })
+    .await;
+
+    match res {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => {
+            log::warn!("Health check couldn't read the TLS certificate -> {}", e);
+            (HealthStatus::Warn, "unable to check expiry".to_string())
+        }
+        Err(e) => {
+            log::warn!("Health check couldn't read the TLS certificate -> {}", e);
+            (HealthStatus::Warn, "unable to check expiry".to_string())
+        }
+    }
+}
+
+// The agent keeps serving from its cached session without the control server, so this only warns
+async fn check_control_server(offline: bool) -> (HealthStatus, String) {
+    match control_plane::get().ping().await {
+        Ok(_) if offline => (HealthStatus::Warn, "reachable, the agent hasn't re-synced yet".to_string()),
+        Ok(_) => (HealthStatus::Ok, "reachable".to_string()),
+        Err(e) => {
+            log::debug!("Health check couldn't reach the control server -> {}", e);
+            (HealthStatus::Warn, "unreachable".to_string())
+        }
+    }
+}
+
+// The last report of `check_public` and when it was made
+static PUBLIC_REPORT: Mutex<Option<(Instant, HealthReport)>> = Mutex::const_new(None);
+
+/// Runs every check concurrently, the control server's only if `include_control_server`.
+pub async fn check(gstate: &'static TsGlobalState, include_control_server: bool) -> HealthReport {
+    let lock = gstate.read().await;
+    let public = lock.public_addr.is_some();
+    let offline = lock.offline;
+    drop(lock);
+
+    let control_server = async {
+        match include_control_server {
+            true => Some(run_check("control_server", check_control_server(offline)).await),
+            false => None,
+        }
+    };
+    let (docker, disk, tunnel, cert, control_server) = tokio::join!(
+        run_check("docker", check_docker()),
+        run_check("disk", check_disk()),
+        run_check("tunnel", check_tunnel(public)),
+        run_check("tls_cert", check_cert()),
+        control_server,
+    );
+    let mut checks = vec![docker, disk, tunnel, cert];
+    checks.extend(control_server);
+    let status = checks
+        .iter()
+        .map(|c| c.status)
+        .max()
+        .unwrap_or(HealthStatus::Ok);
+
+    HealthReport { status, checks }
+}
+
+/// The report for unauthenticated callers, without the control server check and at most
+/// `CACHE_TTL` old. Concurrent callers wait for a single run of the checks.
+pub async fn check_public(gstate: &'static TsGlobalState) -> HealthReport {
+    let mut cache = PUBLIC_REPORT.lock().await;
+    if let Some((at, report)) = cache.as_ref() {
+        if at.elapsed() < CACHE_TTL {
+            return report.clone();
+        }
+    }
+    let report = check(gstate, false).await;
+    *cache = Some((Instant::now(), report.clone()));
+    report
+}
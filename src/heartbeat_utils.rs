@@ -0,0 +1,120 @@
+use crate::{
+    auth_utils, config_utils, control_plane, diagnostics, docker_utils,
+    global_state::TsGlobalState,
+};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// First retry after a failed heartbeat, doubled on every further failure
+const RETRY_BASE: Duration = Duration::from_secs(5);
+
+/// The parts of `diagnostics::measure` that change while the node is running.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiagsSummary {
+    pub cpu: Option<String>,
+    pub hardware_threads: Option<usize>,
+    pub mem_total: Option<f64>,
+    pub mem_free: Option<f64>,
+}
+
+/// What the node periodically reports about itself to the control plane.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub node_id: String,
+    pub name: String,
+    pub public_addr: Option<String>,
+    pub agent_version: String,
+    pub sent_at: u64,
+    pub uptime_secs: u64,
+    pub diagnostics: DiagsSummary,
+    // `None` if docker couldn't be asked
+    pub containers_running: Option<usize>,
+    pub containers_total: Option<usize>,
+}
+
+async fn build_heartbeat(node_id: String, name: String, public_addr: Option<String>, started: Instant) -> Heartbeat {
+    let (diags, counts) = tokio::join!(
+        diagnostics::measure(&node_id, &name),
+        docker_utils::count_containers()
+    );
+    let (containers_running, containers_total) = match counts {
+        Ok((running, total)) => (Some(running), Some(total)),
+        Err(e) => {
+            log::debug!("Failed to count containers for heartbeat -> {}", e);
+            (None, None)
+        }
+    };
+
+    Heartbeat {
+        node_id,
+        name,
+        public_addr,
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        sent_at: auth_utils::now_secs(),
+        uptime_secs: started.elapsed().as_secs(),
+        diagnostics: DiagsSummary {
+            cpu: diags.cpu,
+            hardware_threads: diags.hardware_threads,
+            mem_total: diags.mem_total,
+            mem_free: diags.mem_free,
+        },
+        containers_running,
+        containers_total,
+    }
+}
+
+fn retry_delay(failures: u32, max: Duration) -> Duration {
+    RETRY_BASE
+        .checked_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .map_or(max, |d| d.min(max))
+}
+
+/// Reports the node's liveness to the control plane every `heartbeat.interval_secs`. Failed
+/// heartbeats are retried with exponential backoff, capped at `heartbeat.max_backoff_secs`.
+/// Nothing is sent while the agent is offline, `session_utils::resync_loop` reconnects it first.
+pub async fn heartbeat_loop(gstate: &'static TsGlobalState) {
+    let config = &config_utils::get().heartbeat;
+    if !config.enabled {
+        return;
+    }
+    let interval = Duration::from_secs(config.interval_secs);
+    let max_backoff = Duration::from_secs(config.max_backoff_secs);
+    let started = Instant::now();
+    let mut failures = 0;
+
+    loop {
+        let lock = gstate.read().await;
+        let creds = match (&lock.email, &lock.pass_sha256, &lock.node_id, &lock.name) {
+            (Some(e), Some(p), Some(id), Some(n)) if !lock.offline => {
+                Some((e.clone(), p.clone(), id.clone(), n.clone(), lock.public_addr.clone()))
+            }
+            _ => None,
+        };
+        drop(lock);
+
+        if let Some((email, pass_sha256, node_id, name, public_addr)) = creds {
+            let beat = build_heartbeat(node_id, name, public_addr, started).await;
+            match control_plane::get().heartbeat(&email, &pass_sha256, &beat).await {
+                Ok(_) => {
+                    if failures > 0 {
+                        log::info!("Heartbeat delivered again after {} failed attempts.", failures);
+                    }
+                    failures = 0;
+                }
+                Err(e) => {
+                    failures += 1;
+                    // Don't flood the logs while the control plane is down
+                    if failures == 1 || failures % 10 == 0 {
+                        log::warn!("Failed to send heartbeat ({} in a row) -> {}", failures, e);
+                    }
+                }
+            }
+        }
+
+        let delay = match failures {
+            0 => interval,
+            n => retry_delay(n, max_backoff),
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
@@ -0,0 +1,221 @@
+/*
+Resolves the values the agent needs at startup (credentials, node name, ngrok token, ...)
+without requiring someone to sit at the terminal.
+
+Every input is looked up, in order, from:
+    1. a command line flag              e.g. `--email me@example.com`
+    2. an environment variable          e.g. `TYB_EMAIL`
+    3. the credentials file             e.g. `email = me@example.com`
+    4. an interactive prompt            only when running interactively
+
+The credentials file is given with `--credentials <path>` or `TYB_CREDENTIALS_FILE` and
+holds one `key = value` pair per line (`#` starts a comment).
+*/
+
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{stdin, IsTerminal},
+};
+use tynkerbase_universal::crypt_utils;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Input {
+    Email,
+    Password,
+    NodeName,
+    NgrokToken,
+}
+
+impl Input {
+    fn flag(&self) -> &'static str {
+        match self {
+            Input::Email => "--email",
+            Input::Password => "--password-file",
+            Input::NodeName => "--node-name",
+            Input::NgrokToken => "--ngrok-token",
+        }
+    }
+
+    fn env_var(&self) -> &'static str {
+        match self {
+            Input::Email => "TYB_EMAIL",
+            Input::Password => "TYB_PASSWORD",
+            Input::NodeName => "TYB_NODE_NAME",
+            Input::NgrokToken => "TYB_NGROK_TOKEN",
+        }
+    }
+
+    fn file_key(&self) -> &'static str {
+        match self {
+            Input::Email => "email",
+            Input::Password => "password",
+            Input::NodeName => "node_name",
+            Input::NgrokToken => "ngrok_token",
+        }
+    }
+
+    fn is_secret(&self) -> bool {
+        matches!(self, Input::Password | Input::NgrokToken)
+    }
+}
+
+pub struct StartupInputs {
+    // Leading words that aren't flags, i.e. the subcommand
+    positionals: Vec<String>,
+    flags: HashMap<String, String>,
+    switches: Vec<String>,
+    file_values: HashMap<String, String>,
+    interactive: bool,
+}
+
+fn is_truthy(s: &str) -> bool {
+    matches!(s.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "y")
+}
+
+fn parse_credentials_file(path: &str) -> Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read credentials file `{}` -> {}", path, e))?;
+
+    let mut values = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, val) = line.split_once('=').ok_or_else(|| {
+            anyhow!("Line {} of credentials file `{}` is not a `key = value` pair", i + 1, path)
+        })?;
+        let val = val.trim().trim_matches('"');
+        values.insert(key.trim().to_string(), val.to_string());
+    }
+    Ok(values)
+}
+
+impl StartupInputs {
+    /// Parses the process' command line and environment.
+    pub fn from_env() -> Result<Self> {
+        Self::from_args(env::args().skip(1).collect())
+    }
+
+    pub fn from_args(args: Vec<String>) -> Result<Self> {
+        let mut positionals = vec![];
+        let mut flags = HashMap::new();
+        let mut switches = vec![];
+
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next_if(|a| !a.starts_with("--")) {
+            positionals.push(arg);
+        }
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                return Err(anyhow!("Unexpected argument `{}`", arg));
+            }
+            if let Some((flag, val)) = arg.split_once('=') {
+                flags.insert(flag.to_string(), val.to_string());
+            }
+            else if args.peek().is_some_and(|next| !next.starts_with("--")) {
+                flags.insert(arg, args.next().unwrap());
+            }
+            else {
+                switches.push(arg);
+            }
+        }
+
+        let cred_path = flags
+            .get("--credentials")
+            .cloned()
+            .or_else(|| env::var("TYB_CREDENTIALS_FILE").ok());
+        let file_values = match cred_path {
+            Some(path) => parse_credentials_file(&path)?,
+            None => HashMap::new(),
+        };
+
+        let non_interactive = switches.iter().any(|s| s == "--non-interactive")
+            || env::var("TYB_NON_INTERACTIVE").is_ok_and(|v| is_truthy(&v));
+        let interactive = !non_interactive && stdin().is_terminal();
+
+        Ok(StartupInputs {
+            positionals,
+            flags,
+            switches,
+            file_values,
+            interactive,
+        })
+    }
+
+    pub fn positionals(&self) -> &[String] {
+        &self.positionals
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    pub fn has_switch(&self, switch: &str) -> bool {
+        self.switches.iter().any(|s| s == switch)
+    }
+
+    /// Value of a flag that isn't one of the `Input`s, e.g. `--config <path>`.
+    pub fn flag(&self, flag: &str) -> Option<&str> {
+        self.flags.get(flag).map(|v| v.as_str())
+    }
+
+    /// Looks the input up from the flags, environment and credentials file.
+    pub fn get(&self, input: Input) -> Result<Option<String>> {
+        if let Some(val) = self.flags.get(input.flag()) {
+            // Passwords are read from a file so they don't show up in `ps`
+            if let Input::Password = input {
+                let password = fs::read_to_string(val)
+                    .map_err(|e| anyhow!("Failed to read password file `{}` -> {}", val, e))?;
+                return Ok(Some(password.trim_end_matches(['\r', '\n']).to_string()));
+            }
+            return Ok(Some(val.clone()));
+        }
+        if let Ok(val) = env::var(input.env_var()) {
+            return Ok(Some(val));
+        }
+        Ok(self.file_values.get(input.file_key()).cloned())
+    }
+
+    /// Like `get`, but prompts for the value when running interactively and fails
+    /// with an explanation of where the value can come from otherwise.
+    pub fn require(&self, input: Input, prompt: &str) -> Result<String> {
+        if let Some(val) = self.get(input)? {
+            return Ok(val);
+        }
+        if !self.interactive {
+            return Err(anyhow!(
+                "Missing required input, provide it with `{}`, the `{}` environment variable \
+                or `{}` in the credentials file",
+                input.flag(),
+                input.env_var(),
+                input.file_key()
+            ));
+        }
+        if input.is_secret() {
+            return Ok(crypt_utils::prompt_secret(prompt));
+        }
+        Ok(crypt_utils::prompt(prompt))
+    }
+
+    /// Asks a yes/no question. `--yes` (or `TYB_ASSUME_YES`) answers yes, and running
+    /// non-interactively without it is an error.
+    pub fn confirm(&self, prompt: &str) -> Result<bool> {
+        let assume_yes = self.has_switch("--yes")
+            || env::var("TYB_ASSUME_YES").is_ok_and(|v| is_truthy(&v))
+            || self.file_values.get("assume_yes").is_some_and(|v| is_truthy(v));
+        if assume_yes {
+            return Ok(true);
+        }
+        if !self.interactive {
+            return Err(anyhow!(
+                "Confirmation required ({}), pass `--yes` or set `TYB_ASSUME_YES=1` to accept",
+                prompt.trim().trim_end_matches(':')
+            ));
+        }
+        let res = crypt_utils::prompt(prompt);
+        Ok(res.trim().eq_ignore_ascii_case("y"))
+    }
+}
@@ -0,0 +1,343 @@
+/*
+The agent's own log. Records go through the `log` macros and end up both on stdout (for the
+terminal or journald) and, as JSON lines, in `{root_dir}/logs/agent.log`, which is rotated
+like the audit log. Key-value pairs passed to the macros are kept as structured fields, e.g.
+
+    log::warn!(ip = ip.to_string().as_str(); "Invalid credentials");
+
+Requests are logged once they are answered by `RequestLogger`, with an id that is also sent
+back in the `X-Request-Id` header.
+*/
+
+use crate::{
+    auth_utils::{self, RequestIdentity},
+    config_utils,
+};
+use anyhow::{anyhow, Result};
+use log::{kv, Level, LevelFilter, Log, Metadata, Record};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const REQUEST_ID_LEN: usize = 12;
+// Error bodies longer than this are cut before being logged
+const MAX_LOGGED_BODY: usize = 2048;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    // Milliseconds since the unix epoch
+    pub ts_ms: u64,
+    pub level: String,
+    pub target: String,
+    pub msg: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+pub struct AgentLogger {
+    level: LevelFilter,
+    max_bytes: u64,
+    max_files: usize,
+    // `None` if the log file couldn't be opened, records then only go to stdout
+    file: Mutex<Option<LogFile>>,
+}
+
+fn get_log_dir() -> String {
+    format!("{}/logs", config_utils::root_dir())
+}
+
+fn get_log_path(index: usize) -> String {
+    match index {
+        0 => format!("{}/agent.log", get_log_dir()),
+        i => format!("{}/agent.log.{}", get_log_dir(), i),
+    }
+}
+
+fn open_log_file() -> Result<LogFile> {
+    let dir = get_log_dir();
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+    let path = get_log_path(0);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| anyhow!("Failed to open `{path}` -> {e}"))?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    Ok(LogFile { file, size })
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Collects a record's key-value pairs
+struct FieldCollector(BTreeMap<String, String>);
+
+impl<'kvs> kv::VisitSource<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        if !value.is_empty() {
+            self.0.insert(key.to_string(), value);
+        }
+        Ok(())
+    }
+}
+
+impl AgentLogger {
+    fn rotate(&self) -> Result<()> {
+        let oldest = get_log_path(self.max_files);
+        if Path::new(&oldest).exists() {
+            fs::remove_file(&oldest).map_err(|e| anyhow!("Failed to remove `{oldest}` -> {e}"))?;
+        }
+        for i in (0..self.max_files).rev() {
+            let from = get_log_path(i);
+            if Path::new(&from).exists() {
+                fs::rename(&from, get_log_path(i + 1))
+                    .map_err(|e| anyhow!("Failed to rotate `{from}` -> {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_to_file(&self, line: &str) -> Result<()> {
+        let mut lock = self.file.lock().map_err(|e| anyhow!("Log file lock poisoned -> {e}"))?;
+        let log_file = match lock.as_mut() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        if log_file.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+            *log_file = open_log_file()?;
+        }
+        log_file
+            .file
+            .write_all(line.as_bytes())
+            .map_err(|e| anyhow!("Failed to write to log file -> {e}"))?;
+        log_file.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Log for AgentLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Other crates (rocket, hyper, ...) are only heard from when something is wrong
+        let max = match metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            true => self.level,
+            false => self.level.min(LevelFilter::Warn),
+        };
+        metadata.level() <= max
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut fields = FieldCollector(BTreeMap::new());
+        let _ = record.key_values().visit(&mut fields);
+        let entry = LogRecord {
+            ts_ms: now_ms(),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            msg: record.args().to_string(),
+            fields: fields.0,
+        };
+
+        let fields_str: String = entry.fields.iter().map(|(k, v)| format!(" {k}={v}")).collect();
+        println!("[{}] {}{}", entry.level, entry.msg, fields_str);
+
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        line.push('\n');
+        if let Err(_e) = self.write_to_file(&line) {
+            #[cfg(debug_assertions)]
+            println!("Failed to write log file -> {}", _e);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut lock) = self.file.lock() {
+            if let Some(f) = lock.as_mut() {
+                let _ = f.file.flush();
+            }
+        }
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Installs the agent's logger. Must be called once the config is loaded and before rocket
+/// is launched (rocket only installs its own logger if none is set).
+pub fn init() {
+    let config = &config_utils::get().logging;
+    let level = LevelFilter::from_str(&config.level).unwrap_or(LevelFilter::Info);
+    let file = match open_log_file() {
+        Ok(f) => Some(f),
+        Err(e) => {
+            println!("Warning: logging to stdout only -> {}", e);
+            None
+        }
+    };
+
+    let logger = AgentLogger {
+        level,
+        max_bytes: config.max_file_mb * 1_000_000,
+        max_files: config.max_files,
+        file: Mutex::new(file),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level.max(LevelFilter::Warn));
+    }
+}
+
+/// Filters applied by `query`, every one of them optional.
+#[derive(Debug, Default)]
+pub struct LogQuery<'a> {
+    pub min_level: Option<Level>,
+    pub since_ms: Option<u64>,
+    pub contains: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+/// Returns the last `limit` records (oldest first) that match `filter`.
+pub fn query(filter: &LogQuery, limit: usize) -> Result<Vec<LogRecord>> {
+    let max_files = config_utils::get().logging.max_files;
+
+    let mut records = vec![];
+    for i in (0..=max_files).rev() {
+        let text = match fs::read_to_string(get_log_path(i)) {
+            Ok(t) => t,
+            _ => continue,
+        };
+        for line in text.lines() {
+            let record: LogRecord = match serde_json::from_str(line) {
+                Ok(r) => r,
+                _ => continue,
+            };
+            let level = Level::from_str(&record.level).unwrap_or(Level::Trace);
+            if filter.min_level.is_some_and(|min| level > min) {
+                continue;
+            }
+            if filter.since_ms.is_some_and(|s| record.ts_ms < s) {
+                continue;
+            }
+            if filter.contains.is_some_and(|c| !record.msg.contains(c)) {
+                continue;
+            }
+            if filter.request_id.is_some_and(|id| record.fields.get("request_id").map(|r| r.as_str()) != Some(id)) {
+                continue;
+            }
+            records.push(record);
+        }
+    }
+
+    let skip = records.len().saturating_sub(limit);
+    Ok(records.split_off(skip))
+}
+
+struct RequestStart {
+    id: String,
+    at: Instant,
+}
+
+fn request_start<'r>(req: &'r Request<'_>) -> &'r RequestStart {
+    req.local_cache(|| RequestStart {
+        id: String::new(),
+        at: Instant::now(),
+    })
+}
+
+/// The id `RequestLogger` gave the request, so guards and routes can log with it.
+pub fn request_id<'r>(req: &'r Request<'_>) -> &'r str {
+    &request_start(req).id
+}
+
+/// Logs every answered request with its id, client, identity, status and duration. For
+/// failed requests the error message sent back is logged too.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        req.local_cache(|| RequestStart {
+            id: thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(REQUEST_ID_LEN)
+                .map(char::from)
+                .collect(),
+            at: Instant::now(),
+        });
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let start = request_start(req);
+        res.set_header(Header::new(REQUEST_ID_HEADER, start.id.clone()));
+
+        let status = res.status();
+        let mut error = None;
+        if status.code >= 400 {
+            if let Ok(body) = res.body_mut().to_string().await {
+                let cut: String = body.chars().take(MAX_LOGGED_BODY).collect();
+                res.set_sized_body(body.len(), std::io::Cursor::new(body));
+                error = Some(cut);
+            }
+        }
+
+        let level = match status.code {
+            500.. => Level::Error,
+            400.. => Level::Warn,
+            _ => Level::Info,
+        };
+        let identity = req.local_cache(|| RequestIdentity(None)).0.clone().unwrap_or_default();
+        let client_ip = auth_utils::client_ip(req).map(|ip| ip.to_string()).unwrap_or_default();
+        // Only the path, query strings can carry credentials and secrets
+        log::log!(
+            level,
+            request_id = start.id.as_str(),
+            method = req.method().as_str(),
+            path = req.uri().path().as_str(),
+            status = status.code,
+            duration_ms = start.at.elapsed().as_millis() as u64,
+            client_ip = client_ip.as_str(),
+            identity = identity.as_str(),
+            error = error.as_deref().unwrap_or("");
+            "{} {} -> {}",
+            req.method(),
+            req.uri().path(),
+            status.code
+        );
+    }
+}
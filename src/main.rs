@@ -1,25 +1,52 @@
+mod audit_utils;
+mod auth_utils;
+mod ban_utils;
+mod cli_utils;
+mod config_utils;
 mod consts;
+mod control_plane;
 mod dep_utils;
 mod diagnostics;
 mod docker_utils;
 mod global_state;
+mod health_utils;
+mod heartbeat_utils;
+mod input_utils;
+mod log_utils;
 mod ngrok_utils;
+mod node_utils;
+mod proj_meta_utils;
 mod proj_utils;
+mod secret_utils;
+mod session_utils;
+mod shutdown_utils;
+mod signing_utils;
+mod snapshot_utils;
+mod systemd_utils;
 mod tls_utils;
+mod upload_utils;
 
 use anyhow::anyhow;
-use bincode;
-use consts::{AGENT_ROOTDIR_PATH, SERVER_ENDPOINT, CONTAINER_MOD, IMAGE_MOD};
+use audit_utils::{AuditFairing, AuditProject};
+use auth_utils::{Identity, Permission, RequestIdentity};
+use cli_utils::Command;
+use config_utils::AgentConfig;
+use control_plane::LoginResult;
 use global_state::{GlobalState, TsGlobalState};
-use rand::{thread_rng, Rng};
+use input_utils::{Input, StartupInputs};
+use node_utils::NodeIdentity;
+use proj_utils::{ProjDiff, ProjName};
+use session_utils::CachedSession;
+use upload_utils::UploadSession;
 use rocket::{
     self, 
     catchers, 
-    config::{Config, TlsConfig}, 
+    config::{Config, MutualTls, TlsConfig}, 
+    fairing::AdHoc, 
     data::{Limits, ToByteUnit}, 
     figment::Figment, 
     http::Status, 
-    launch, 
+    mtls::Certificate, 
     outcome::Outcome, 
     request::{self, FromRequest}, 
     response::status::Custom, 
@@ -28,7 +55,7 @@ use rocket::{
 };
 
 use tynkerbase_universal::{
-    constants::{LINUX_TYNKERBASE_PATH, TYB_APIKEY_HTTP_HEADER},
+    constants::TYB_APIKEY_HTTP_HEADER,
     crypt_utils::{self, compression_utils, hash_utils, BinaryPacket},
     file_utils::FileCollection,
     netwk_utils::ProjConfig,
@@ -36,131 +63,318 @@ use tynkerbase_universal::{
 };
 
 use std::{
-    env,
+    net::IpAddr,
     process,
-    path::PathBuf,
+    str::FromStr,
     sync::OnceLock,
+    time::{Duration, Instant},
 };
 
 // Suppress warning being thrown since OS is only used in release mode
 #[allow(unused_imports)]
 use std::{env::consts::OS, fs, path::Path};
 
-async fn load_apikey(res: reqwest::Response, pass_sha384: &str) -> String {
-    if !res.status().is_success() {
-        println!("Error: Failed to login to server: {:#?}", res);
-        process::exit(1);
+async fn prompt_node_name(email: &str, pass_sha256: &str, inputs: &StartupInputs) -> String {
+    loop {
+        let name = match inputs.require(Input::NodeName, "Enter a name for this node: ") {
+            Ok(n) => n,
+            Err(e) => {
+                log::error!("No node name given -> {}", e);
+                process::exit(1);
+            }
+        };
+
+        let err = match node_utils::validate_node_name(&name) {
+            Err(e) => e.to_string(),
+            Ok(_) => match control_plane::get().node_name_taken(email, pass_sha256, &name).await {
+                Ok(false) => return name,
+                Ok(true) => format!("node name `{}` already exists", name),
+                Err(e) => {
+                    log::error!("Unable to check the node name with the control server -> {}", e);
+                    process::exit(1);
+                }
+            },
+        };
+
+        log::error!("{}", err);
+        if !inputs.is_interactive() {
+            process::exit(1);
+        }
+    }
+}
+
+async fn load_node_info(email: &str, pass_sha256: &str, inputs: &StartupInputs) -> (String, String) {
+    match node_utils::load_identity() {
+        Ok(Some(identity)) => return (identity.node_id, identity.name),
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(1);
+        }
     }
 
-    let salt = res
-        .text()
-        .await
-        .expect("unable to extract text from response");
+    let name = prompt_node_name(email, pass_sha256, inputs).await;
+    let identity = NodeIdentity::new(name);
+    if let Err(e) = node_utils::save_identity(&identity) {
+        log::error!("Failed to save node identity -> {}", e);
+        process::exit(1);
+    }
+    (identity.node_id, identity.name)
+}
 
-    crypt_utils::gen_apikey(pass_sha384, &salt)
+struct ApiKey {
+    identity: Identity,
+    // Set when the request was signed, the body must hash to this value
+    body_sha256: Option<String>,
 }
 
-async fn prompt_node_name(email: &str, pass_sha256: &str) -> String {
-    loop {
-        let name = crypt_utils::prompt("Enter a name for this node: ");
-
-        let endpoint = format!(
-            "{SERVER_ENDPOINT}/ngrok/check-node-exists/name?\
-            email={email}&\
-            pass_sha256={pass_sha256}&\
-            name={}",
-            &name
-        );
+impl ApiKey {
+    fn new(identity: Identity) -> Self {
+        ApiKey { identity, body_sha256: None }
+    }
 
-        let res = reqwest::get(endpoint).await.unwrap();
+    fn authorize<T: From<String>>(&self, required: Permission, proj_name: Option<&str>) -> Result<(), Custom<T>> {
+        self.identity
+            .check(required, proj_name)
+            .map_err(|e| Custom(Status::Forbidden, T::from(e.to_string())))
+    }
 
-        if !res.status().is_success() {
-            println!("Error communicating with database. This is an error with tynkerbase.");
-            #[cfg(debug_assertions)]
-            {
-                println!("Error Res -> \n{:?}\n\n", res);
-            }
-            process::exit(1);
-        }
+    fn authorize_node_wide<T: From<String>>(&self, required: Permission) -> Result<(), Custom<T>> {
+        self.identity
+            .check_node_wide(required)
+            .map_err(|e| Custom(Status::Forbidden, T::from(e.to_string())))
+    }
 
-        let text = res.text().await.unwrap();
-        if text == "false" {
-            return name;
+    // Ensures the body of a signed request is the one that was signed
+    fn verify_body<T: From<String>>(&self, data: &[u8]) -> Result<(), Custom<T>> {
+        match &self.body_sha256 {
+            Some(expected) if !auth_utils::constant_time_eq(
+                expected.to_ascii_lowercase().as_bytes(),
+                signing_utils::sha256_hex(data).as_bytes(),
+            ) => Err(Custom(
+                Status::Forbidden,
+                T::from("Request body does not match the signed content hash".to_string()),
+            )),
+            _ => Ok(()),
         }
+    }
 
-        println!("\n\nError: node name `{}` already exists: ", name);
+    // Drops lines of `docker` output that don't belong to a project this key is scoped to.
+    // The first line (the table header) is always kept. A line belongs to a project if one of
+    // its columns (split on `|||`, or whitespace for `docker images`) is exactly the project's
+    // container or image name, so `app` doesn't match `webapp`.
+    fn filter_docker_output(&self, output: String) -> String {
+        let projects = match &self.identity.projects {
+            Some(p) => p,
+            None => return output,
+        };
+
+        let (container_suffix, image_suffix) = (config_utils::container_suffix(), config_utils::image_suffix());
+        let names: Vec<String> = projects
+            .iter()
+            .flat_map(|p| [format!("{p}{container_suffix}"), format!("{p}{image_suffix}")])
+            .collect();
+        output
+            .lines()
+            .enumerate()
+            .filter(|(i, line)| {
+                let fields: Vec<&str> = match line.contains("|||") {
+                    true => line.split("|||").map(|f| f.trim()).collect(),
+                    false => line.split_whitespace().collect(),
+                };
+                // Images can be listed with their tag, e.g. `app__tyb_image:latest`
+                *i == 0 || fields
+                    .iter()
+                    .map(|f| f.split(':').next().unwrap_or(f))
+                    .any(|f| names.iter().any(|n| n == f))
+            })
+            .map(|(_, line)| line)
+            .collect::<Vec<&str>>()
+            .join("\n")
     }
 }
 
-async fn load_node_info(email: &str, pass_sha256: &str) -> (String, String) {
-    // Generate/read Server ID & name
-    let id_len = 32;
-    let mut path_str = format!("{}/data", AGENT_ROOTDIR_PATH);
-    let path = Path::new(&path_str);
-    if !path.exists() {
-        fs::create_dir_all(&path).unwrap();
-    }
-    path_str.push_str("/node-info.bin");
-    let path = Path::new(&path_str);
+// Authenticates a request signed as described in `signing_utils`
+async fn verify_signed_request(req: &Request<'_>, signature: &str) -> Option<ApiKey> {
+    let headers = req.headers();
+    let signed = signing_utils::SignedHeaders {
+        signature,
+        timestamp: headers.get_one(signing_utils::TIMESTAMP_HEADER)?,
+        nonce: headers.get_one(signing_utils::NONCE_HEADER)?,
+        content_sha256: headers.get_one(signing_utils::CONTENT_SHA256_HEADER)?,
+        key_name: headers.get_one(signing_utils::KEY_NAME_HEADER),
+    };
 
-    if path.exists() {
-        let res = fs::read(&path_str).unwrap();
-        match bincode::deserialize(&res) {
-            Ok(r) => return r,
-            _ => {
-                fs::remove_file(&path_str).unwrap();
-            }
+    let gstate = get_global();
+    let mut lock = gstate.write().await;
+
+    let (identity, key_sha256) = match signed.key_name {
+        Some(key_name) => {
+            let tok = lock.tokens.iter().find(|t| t.name == key_name)?;
+            (Identity::from(tok), tok.token_sha256.clone())
+        }
+        None => {
+            let key_sha256 = hash_utils::sha256(lock.tyb_apikey.as_ref()?);
+            (Identity::master(), key_sha256)
+        }
+    };
+
+    let path_and_query = req.uri().to_string();
+    let mut res = signing_utils::verify(
+        &signed,
+        req.method().as_str(),
+        &path_and_query,
+        &key_sha256,
+        &mut lock.nonces,
+    );
+
+    // Requests signed with the key replaced by the last rotation are accepted during its grace period
+    if res.is_err() && signed.key_name.is_none() {
+        let prev_key_sha256 = lock.valid_apikeys().get(1).map(|k| hash_utils::sha256(k));
+        if let Some(prev_key_sha256) = prev_key_sha256 {
+            res = signing_utils::verify(
+                &signed,
+                req.method().as_str(),
+                &path_and_query,
+                &prev_key_sha256,
+                &mut lock.nonces,
+            );
         }
     }
+    if let Err(e) = res {
+        log::warn!(request_id = log_utils::request_id(req); "Invalid request signature -> {}", e);
+        return None;
+    }
 
-    let id = (0..id_len)
-        .map(|_| thread_rng().gen_range(97..97 + 26) as u8)
-        .collect();
-    let id = String::from_utf8(id).unwrap();
-    fs::write(&path_str, &id).unwrap();
-    let name = prompt_node_name(email, pass_sha256);
-    let name = name.await;
+    Some(ApiKey {
+        identity,
+        body_sha256: Some(signed.content_sha256.to_string()),
+    })
+}
+
+// Parses a comma separated list of project names
+fn parse_proj_list<T: From<String>>(projects: &str) -> Result<Vec<String>, Custom<T>> {
+    projects
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_proj_name(s).map(|n| n.as_str().to_string()))
+        .collect()
+}
 
-    let result = (id, name);
-    let binary = bincode::serialize(&result).unwrap();
-    fs::write(path, binary).unwrap();
-    result
+fn parse_proj_name<T: From<String>>(name: &str) -> Result<ProjName, Custom<T>> {
+    ProjName::parse(name).map_err(|e| Custom(Status::BadRequest, T::from(e.to_string())))
 }
 
-struct ApiKey(#[allow(unused)] String);
+// Picks the status for a failed `proj_utils::stage_upload` (an upload, diff or restore).
+// Either way the project was left as it was.
+fn upload_error(context: &str, e: anyhow::Error) -> Custom<String> {
+    let msg = e.to_string();
+    let status = if msg.contains("does not exist") {
+        Status::NotFound
+    } else if msg.contains("already in progress") {
+        Status::Conflict
+    } else if msg.contains("not a relative path") || msg.contains("contains no files") {
+        Status::BadRequest
+    } else {
+        Status::InternalServerError
+    };
+    Custom(status, format!("{context} -> {msg}"))
+}
 
 #[rocket::async_trait]
 impl<'a> FromRequest<'a> for ApiKey {
     type Error = ();
 
     async fn from_request(req: &'a Request<'_>) -> request::Outcome<Self, Self::Error> {
-        match req.headers().get_one(TYB_APIKEY_HTTP_HEADER) {
-            Some(key) => {
-                let gstate = get_global();
-                let lock = gstate.read().await;
-                let actual_key = lock.tyb_apikey.as_ref().unwrap();
-                if key == actual_key {
-                    return Outcome::Success(ApiKey(key.to_string()));
-                }
-                #[cfg(debug_assertions)] {
-                    println!("\n\nAUTH ERROR: key `{}...` is invalid.\n", &key[..10]);
+        let gstate = get_global();
+        let ip = auth_utils::client_ip(req);
+
+        if let Some(ip) = &ip {
+            let lock = gstate.read().await;
+            if let Some(remaining) = lock.bans.ban_remaining(ip) {
+                log::debug!(
+                    request_id = log_utils::request_id(req);
+                    "Rejected `{}`, banned for another {:?}", ip, remaining
+                );
+                return Outcome::Error((Status::TooManyRequests, ()));
+            }
+        }
+
+        let signature = req.headers().get_one(signing_utils::SIGNATURE_HEADER);
+        let api_key = if let Some(signature) = signature {
+            verify_signed_request(req, signature).await
+        }
+        else if let Some(key) = req.headers().get_one(TYB_APIKEY_HTTP_HEADER) {
+            let lock = gstate.read().await;
+            let is_master = lock
+                .valid_apikeys()
+                .iter()
+                .fold(false, |acc, k| acc | auth_utils::constant_time_eq(key.as_bytes(), k.as_bytes()));
+            if is_master {
+                Some(ApiKey::new(Identity::master()))
+            }
+            else {
+                auth_utils::find_token(&lock.tokens, key).map(|t| ApiKey::new(Identity::from(t)))
+            }
+        }
+        else if let Outcome::Success(cert) = req.guard::<Certificate<'_>>().await {
+            let lock = gstate.read().await;
+            let serial = cert.serial().to_str_radix(16);
+            cert.subject()
+                .common_name()
+                .and_then(|cn| auth_utils::find_client_cert(&lock.client_certs, &serial, cn))
+                .map(|c| ApiKey::new(Identity::from(c)))
+        }
+        else {
+            None
+        };
+
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                return match api_key {
+                    Some(api_key) => {
+                        req.local_cache(|| RequestIdentity(Some(api_key.identity.name.clone())));
+                        Outcome::Success(api_key)
+                    }
+                    None => Outcome::Error((Status::Forbidden, ())),
+                };
+            }
+        };
+
+        match api_key {
+            Some(api_key) => {
+                req.local_cache(|| RequestIdentity(Some(api_key.identity.name.clone())));
+                if gstate.read().await.bans.has_failures(&ip) {
+                    gstate.write().await.bans.record_success(&ip);
                 }
-                Outcome::Error((Status::Forbidden, ()))
+                Outcome::Success(api_key)
             }
-            _ => {
-                #[cfg(debug_assertions)] {
-                    println!("\n\nAUTH ERROR: No API key provided.\n\n")
+            None => {
+                let ban = gstate.write().await.bans.record_failure(ip);
+                log::warn!(request_id = log_utils::request_id(req); "Invalid or missing credentials from `{}`", ip);
+                if let Some(ban) = ban {
+                    log::warn!("Banning `{}` for {:?}", ip, ban);
                 }
                 Outcome::Error((Status::Forbidden, ()))
-            },
+            }
         }
     }
 }
 
 #[rocket::get("/create-proj?<name>&<confirm>")]
-async fn create_proj(name: &str, confirm: Option<bool>, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn create_proj(name: &str, confirm: Option<bool>, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+
     let confirm = confirm.unwrap_or(true);
-    let res = proj_utils::create_proj(name);
+    let res = proj_utils::create_proj(&name);
     if let Err(e) = res {
         let e = e.to_string();
         if e.contains("already exists") {
@@ -179,27 +393,335 @@ async fn create_proj(name: &str, confirm: Option<bool>, #[allow(unused)] apikey:
 async fn add_files_to_proj(
     name: &str,
     data: Vec<u8>,
-    #[allow(unused)] apikey: ApiKey,
+    apikey: ApiKey,
 ) -> Custom<String> {
-    let _ = proj_utils::clear_proj(&name);
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+    if let Err(e) = apikey.verify_body(&data) {
+        return e;
+    }
+
+    let files = bincode::deserialize::<BinaryPacket>(&data)
+        .and_then(|packet| bincode::deserialize::<FileCollection>(&packet.data));
+    let files = match files {
+        Ok(f) => f,
+        Err(e) => return Custom(Status::BadRequest, format!("Malformed packet -> {e}")),
+    };
 
-    let packet: BinaryPacket = bincode::deserialize(&data).unwrap();
-    let files: FileCollection = bincode::deserialize(&packet.data).unwrap();
+    let res = tokio::task::spawn_blocking(move || proj_utils::add_files_to_proj(&name, files)).await;
+    match res {
+        Ok(Ok(_)) => Custom(Status::Ok, "success".to_string()),
+        Ok(Err(e)) => upload_error("Error adding files to project", e),
+        Err(e) => Custom(Status::InternalServerError, format!("Error adding files to project -> {e}")),
+    }
+}
 
-    if let Err(e) = proj_utils::add_files_to_proj(name, files) {
-        return Custom(
-            Status::InternalServerError,
-            format!("Error adding files to project -> {e}"),
-        );
+#[rocket::get("/manifest?<name>")]
+async fn proj_manifest(name: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::ReadOnly, Some(name.as_str())) {
+        return e;
     }
 
-    Custom(Status::Ok, "success".to_string())
+    let res = tokio::task::spawn_blocking(move || proj_utils::manifest(&name)).await;
+    let manifest = match res {
+        Ok(Ok(m)) => m,
+        Ok(Err(e)) if e.to_string().contains("does not exist") => {
+            return Custom(Status::NotFound, format!("{e}"));
+        }
+        Ok(Err(e)) => {
+            return Custom(Status::InternalServerError, format!("Error hashing project files -> {e}"));
+        }
+        Err(e) => {
+            return Custom(Status::InternalServerError, format!("Error hashing project files -> {e}"));
+        }
+    };
+
+    match serde_json::to_string(&manifest) {
+        Ok(s) => Custom(Status::Ok, s),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing manifest -> {e}")),
+    }
+}
+
+#[rocket::post("/apply-diff?<name>", data = "<data>")]
+async fn apply_proj_diff(
+    name: &str,
+    data: Vec<u8>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+    if let Err(e) = apikey.verify_body(&data) {
+        return e;
+    }
+
+    let diff = bincode::deserialize::<BinaryPacket>(&data)
+        .and_then(|packet| bincode::deserialize::<ProjDiff>(&packet.data));
+    let diff = match diff {
+        Ok(d) => d,
+        Err(e) => return Custom(Status::BadRequest, format!("Malformed diff -> {e}")),
+    };
+
+    let res = tokio::task::spawn_blocking(move || proj_utils::apply_diff(&name, diff)).await;
+    match res {
+        Ok(Ok(removed)) => Custom(Status::Ok, format!("success, removed {removed} files")),
+        Ok(Err(e)) => upload_error("Error applying diff to project", e),
+        Err(e) => Custom(Status::InternalServerError, format!("Error applying diff to project -> {e}")),
+    }
+}
+
+// Loads an upload session and checks the key may deploy to its project
+fn load_upload_session<T: From<String>>(
+    session: &str,
+    apikey: &ApiKey,
+    audit: &AuditProject,
+) -> Result<UploadSession, Custom<T>> {
+    let session = upload_utils::load_session(session).map_err(|e| {
+        let status = match e.to_string().contains("does not exist") {
+            true => Status::NotFound,
+            false => Status::InternalServerError,
+        };
+        Custom(status, T::from(e.to_string()))
+    })?;
+    audit.set(&session.project);
+    apikey.authorize(Permission::Deploy, Some(&session.project))?;
+    Ok(session)
+}
+
+#[rocket::post("/start?<name>&<size>&<chunk_size>&<sha256>")]
+async fn start_upload(
+    name: &str,
+    size: u64,
+    chunk_size: u64,
+    sha256: Option<String>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+    if !name.path().exists() {
+        return Custom(Status::NotFound, format!("Project `{}` does not exist.", name));
+    }
+
+    let session = match upload_utils::start_session(name.as_str(), size, chunk_size, sha256).await {
+        Ok(s) => s,
+        Err(e) if e.to_string().starts_with("Failed") => {
+            return Custom(Status::InternalServerError, format!("Error starting upload -> {e}"));
+        }
+        Err(e) if e.to_string().contains("already in progress") => return Custom(Status::Conflict, e.to_string()),
+        Err(e) if e.to_string().starts_with("Not enough free disk space") => {
+            return Custom(Status::InsufficientStorage, e.to_string());
+        }
+        Err(e) => return Custom(Status::BadRequest, e.to_string()),
+    };
+    match serde_json::to_string(&session) {
+        Ok(s) => Custom(Status::Ok, s),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing upload session -> {e}")),
+    }
+}
+
+#[rocket::put("/chunk?<session>&<index>&<sha256>", data = "<data>")]
+async fn put_upload_chunk(
+    session: &str,
+    index: u64,
+    sha256: &str,
+    data: Vec<u8>,
+    apikey: ApiKey,
+    audit: &AuditProject,
+) -> Custom<String> {
+    let session = match load_upload_session(session, &apikey, audit) {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.verify_body(&data) {
+        return e;
+    }
+
+    match upload_utils::put_chunk(&session, index, sha256, &data) {
+        Ok(_) => Custom(Status::Ok, "success".to_string()),
+        Err(e) if e.to_string().starts_with("Failed") => {
+            Custom(Status::InternalServerError, format!("Error saving chunk -> {e}"))
+        }
+        Err(e) if e.to_string().contains("already in progress") => Custom(Status::Conflict, e.to_string()),
+        Err(e) => Custom(Status::BadRequest, e.to_string()),
+    }
+}
+
+#[rocket::get("/status?<session>")]
+async fn upload_status(session: &str, apikey: ApiKey, audit: &AuditProject) -> Custom<String> {
+    let session = match load_upload_session(session, &apikey, audit) {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+
+    let status = match upload_utils::status(session) {
+        Ok(s) => s,
+        Err(e) => return Custom(Status::InternalServerError, format!("Error reading upload -> {e}")),
+    };
+    match serde_json::to_string(&status) {
+        Ok(s) => Custom(Status::Ok, s),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing upload status -> {e}")),
+    }
+}
+
+#[rocket::post("/finalize?<session>")]
+async fn finalize_upload(session: &str, apikey: ApiKey, audit: &AuditProject) -> Custom<String> {
+    let session = match load_upload_session(session, &apikey, audit) {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+    let name = match parse_proj_name(&session.project) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+
+    let res = tokio::task::spawn_blocking(move || upload_utils::finalize(&session)).await;
+    let packet = match res {
+        Ok(Ok(p)) => p,
+        Ok(Err(e)) if e.to_string().starts_with("Failed") => {
+            return Custom(Status::InternalServerError, format!("Error reassembling upload -> {e}"));
+        }
+        Ok(Err(e)) if e.to_string().contains("already in progress") => {
+            return Custom(Status::Conflict, e.to_string());
+        }
+        Ok(Err(e)) => return Custom(Status::BadRequest, e.to_string()),
+        Err(e) => {
+            return Custom(Status::InternalServerError, format!("Error reassembling upload -> {e}"));
+        }
+    };
+    let files: FileCollection = match bincode::deserialize(&packet.data) {
+        Ok(f) => f,
+        Err(e) => return Custom(Status::BadRequest, format!("Upload is not a valid file collection -> {e}")),
+    };
+
+    let res = tokio::task::spawn_blocking(move || proj_utils::add_files_to_proj(&name, files)).await;
+    match res {
+        Ok(Ok(_)) => Custom(Status::Ok, "success".to_string()),
+        Ok(Err(e)) => upload_error("Error adding files to project", e),
+        Err(e) => Custom(Status::InternalServerError, format!("Error adding files to project -> {e}")),
+    }
+}
+
+#[rocket::post("/abort?<session>")]
+async fn abort_upload(session: &str, apikey: ApiKey, audit: &AuditProject) -> Custom<String> {
+    let session = match load_upload_session(session, &apikey, audit) {
+        Ok(s) => s,
+        Err(e) => return e,
+    };
+
+    match upload_utils::abort(&session) {
+        Ok(_) => Custom(Status::Ok, "success".to_string()),
+        Err(e) if e.to_string().contains("already in progress") => Custom(Status::Conflict, e.to_string()),
+        Err(e) => Custom(Status::InternalServerError, format!("Error aborting upload -> {e}")),
+    }
+}
+
+#[rocket::get("/list?<name>")]
+async fn list_snapshots(name: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::ReadOnly, Some(name.as_str())) {
+        return e;
+    }
+
+    let snapshots = match snapshot_utils::list(&name) {
+        Ok(s) => s,
+        Err(e) => return Custom(Status::InternalServerError, format!("Error listing snapshots -> {e}")),
+    };
+    match serde_json::to_string(&snapshots) {
+        Ok(s) => Custom(Status::Ok, s),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing snapshots -> {e}")),
+    }
+}
+
+#[rocket::post("/restore?<name>&<version>")]
+async fn restore_snapshot(name: &str, version: String, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+
+    let res = tokio::task::spawn_blocking(move || snapshot_utils::restore(&name, &version)).await;
+    let snapshot = match res {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) if e.to_string().contains("is corrupted") => {
+            return Custom(Status::Conflict, format!("Error restoring snapshot -> {e}"));
+        }
+        Ok(Err(e)) => return upload_error("Error restoring snapshot", e),
+        Err(e) => return Custom(Status::InternalServerError, format!("Error restoring snapshot -> {e}")),
+    };
+    match serde_json::to_string(&snapshot) {
+        Ok(s) => Custom(Status::Ok, s),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing snapshot -> {e}")),
+    }
+}
+
+#[rocket::post("/prune?<name>&<keep>&<older_than_secs>")]
+async fn prune_snapshots(
+    name: &str,
+    keep: Option<usize>,
+    older_than_secs: Option<u64>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Admin, Some(name.as_str())) {
+        return e;
+    }
+    if keep.is_none() && older_than_secs.is_none() {
+        return Custom(Status::BadRequest, "Set `keep`, `older_than_secs` or both".to_string());
+    }
+
+    let _guard = match proj_utils::lock_proj(&name) {
+        Ok(g) => g,
+        Err(e) => return upload_error("Error pruning snapshots", e),
+    };
+    let removed = match snapshot_utils::prune(&name, keep, older_than_secs) {
+        Ok(r) => r,
+        Err(e) => return Custom(Status::InternalServerError, format!("Error pruning snapshots -> {e}")),
+    };
+    match serde_json::to_string(&removed) {
+        Ok(s) => Custom(Status::Ok, s),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing removed snapshots -> {e}")),
+    }
 }
 
 #[rocket::get("/delete-proj?<name>&<confirm>")]
-async fn delete_proj(name: &str, confirm: Option<bool>, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn delete_proj(name: &str, confirm: Option<bool>, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Admin, Some(name.as_str())) {
+        return e;
+    }
+
     let confirm = confirm.unwrap_or(true);
-    let res = proj_utils::delete_proj(name);
+    let res = proj_utils::delete_proj(&name);
     if let Err(e) = res {
         let e = e.to_string();
         if e.contains("does not exist") {
@@ -208,15 +730,70 @@ async fn delete_proj(name: &str, confirm: Option<bool>, #[allow(unused)] apikey:
             }
             return Custom(Status::Conflict, format!("Project does not exist -> {e}"));
         }
+        if e.contains("already in progress") {
+            return Custom(Status::Conflict, e);
+        }
         return Custom(Status::InternalServerError, e);
     }
 
     Custom(Status::Ok, "success".to_string())
 }
 
-#[rocket::get("/list-projects")]
-async fn list_projects(#[allow(unused)] apikey: ApiKey) -> Custom<Vec<u8>> {
-    let res = proj_utils::get_proj_names();
+// Metadata, size and container state of every project, for `list-projects`
+async fn describe_projects(names: Vec<String>) -> Result<Vec<proj_meta_utils::ProjInfo>, String> {
+    let states = match docker_utils::container_states().await {
+        Ok(s) => Some(s),
+        Err(e) => {
+            log::warn!("Failed to get container states -> {}", e);
+            None
+        }
+    };
+
+    let infos = tokio::task::spawn_blocking(move || {
+        let mut infos = vec![];
+        for name in names {
+            let name = ProjName::parse(&name).map_err(|e| e.to_string())?;
+            let meta = proj_meta_utils::load(&name).unwrap_or_else(|e| {
+                log::warn!("{}", e);
+                proj_meta_utils::ProjMeta::default()
+            });
+            let container_state = states
+                .as_ref()
+                .and_then(|s| s.get(&name.container_name()).cloned())
+                .or_else(|| states.is_none().then(|| "unknown".to_string()));
+            infos.push(proj_meta_utils::ProjInfo {
+                name: name.to_string(),
+                meta,
+                size_bytes: proj_meta_utils::size_on_disk(&name.path()),
+                container_state,
+            });
+        }
+        Ok(infos)
+    });
+    infos.await.map_err(|e| e.to_string())?
+}
+
+#[rocket::get("/list-projects?<names_only>")]
+async fn list_projects(names_only: Option<bool>, apikey: ApiKey) -> Custom<Vec<u8>> {
+    let res: Vec<String> = proj_utils::get_proj_names()
+        .into_iter()
+        .filter(|p| apikey.identity.can_access(p))
+        .collect();
+
+    // Clients that only know the bincode list of names ask for it with `names_only=true`
+    if !names_only.unwrap_or(false) {
+        let infos = match describe_projects(res).await {
+            Ok(i) => i,
+            Err(e) => {
+                return Custom(Status::InternalServerError, format!("Error listing projects -> {e}").into_bytes());
+            }
+        };
+        return match serde_json::to_vec(&infos) {
+            Ok(r) => Custom(Status::Ok, r),
+            Err(e) => Custom(Status::InternalServerError, format!("Error serializing projects -> {e}").into_bytes()),
+        };
+    }
+
     let res = match bincode::serialize(&res) {
         Ok(r) => r,
         Err(e) => {
@@ -230,11 +807,19 @@ async fn list_projects(#[allow(unused)] apikey: ApiKey) -> Custom<Vec<u8>> {
 }
 
 #[rocket::get("/purge-project?<name>&<retries>")]
-async fn purge_projects(name: &str, retries:Option<u32>, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn purge_projects(name: &str, retries:Option<u32>, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Admin, Some(name.as_str())) {
+        return e;
+    }
+
     let retries = retries.unwrap_or(2);
 
-    let container_name = format!("{}{CONTAINER_MOD}", name);
-    let image_name = format!("{}{IMAGE_MOD}", name);
+    let container_name = name.container_name();
+    let image_name = name.image_name();
 
     let mut success: [anyhow::Result<()>; 2] = [
         Err(anyhow!("unknown error deleting container")),
@@ -286,7 +871,7 @@ async fn purge_projects(name: &str, retries:Option<u32>, #[allow(unused)] apikey
         );
     }
 
-    if let Err(e) = proj_utils::delete_proj(name) {
+    if let Err(e) = proj_utils::delete_proj(&name) {
         if !e.to_string().contains("does not exist") {
             return Custom(
                 Status::InternalServerError, 
@@ -295,12 +880,27 @@ async fn purge_projects(name: &str, retries:Option<u32>, #[allow(unused)] apikey
         }
     }
 
+    if let Err(e) = secret_utils::delete_all(&name) {
+        return Custom(
+            Status::InternalServerError, 
+            format!("Failed to delete project secrets -> {}", e)
+        );
+    }
+
     Custom(Status::Ok, "success".to_string())
 }
 
 #[rocket::get("/pull-files?<name>")]
-fn pull_proj_files(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<Vec<u8>> {
-    let fc = match proj_utils::load_proj_files(name, None) {
+fn pull_proj_files(name: &str, apikey: ApiKey) -> Custom<Vec<u8>> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::ReadOnly, Some(name.as_str())) {
+        return e;
+    }
+
+    let fc = match proj_utils::load_proj_files(&name, None) {
         Ok(fc) => fc,
         Err(e) => {
             return Custom(
@@ -323,7 +923,11 @@ fn pull_proj_files(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<Vec<u8
 }
 
 #[rocket::post("/start-docker-daemon")]
-async fn start_docker_daemon(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn start_docker_daemon(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
     if let Err(e) = docker_utils::start_daemon().await {
         return Custom(
             Status::InternalServerError,
@@ -335,7 +939,19 @@ async fn start_docker_daemon(#[allow(unused)] apikey: ApiKey) -> Custom<String>
 }
 
 #[rocket::get("/end-docker-daemon")]
-async fn end_docker_daemon(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn end_docker_daemon(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    // The daemon is shared by every instance on the host, a named one must not take it down
+    if let Some(instance) = config_utils::instance() {
+        return Custom(
+            Status::Conflict,
+            format!("Instance `{instance}` shares the docker daemon with the host's other agents, stop it from the default instance"),
+        );
+    }
+
     if let Err(e) = docker_utils::end_daemon().await {
         return Custom(
             Status::InternalServerError,
@@ -347,7 +963,11 @@ async fn end_docker_daemon(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
 }
 
 #[rocket::get("/get-daemon-status")]
-async fn get_daemon_status(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn get_daemon_status(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize(Permission::ReadOnly, None) {
+        return e;
+    }
+
     let status = match docker_utils::get_engine_status().await {
         Ok(b) => b,
         Err(e) => return Custom(Status::Ok, format!("Error getting daemon status: {}", e)),
@@ -357,11 +977,23 @@ async fn get_daemon_status(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
 }
 
 #[rocket::get("/build-img?<name>")]
-async fn build_image(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
-    let mut path = PathBuf::from(LINUX_TYNKERBASE_PATH);
-    path.push(name);
+async fn build_image(name: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
 
-    let img_name = format!("{}{IMAGE_MOD}", name);
+    // The files mustn't change while docker reads them
+    let _guard = match proj_utils::lock_proj(&name) {
+        Ok(g) => g,
+        Err(e) => return upload_error("Error building image", e),
+    };
+    let path = name.path();
+
+    let img_name = name.image_name();
     let path_str = match path.to_str() {
         Some(p) => p,
         None => return Custom(Status::InternalServerError, "Failed to parse path".to_string()),
@@ -371,15 +1003,35 @@ async fn build_image(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<Stri
         return Custom(Status::InternalServerError, format!("Failed to delete image -> {}", e));
     }
 
+    let image_id = match docker_utils::image_id(&img_name).await {
+        Ok(id) => Some(id),
+        Err(e) => {
+            log::warn!("Failed to get the id of image `{}` -> {}", img_name, e);
+            None
+        }
+    };
+    let now = auth_utils::now_secs();
+    if let Err(e) = proj_meta_utils::update(&name, |m| {
+        m.last_build = Some(now);
+        m.image_id = image_id;
+    }) {
+        log::warn!("Failed to update the metadata of project `{}` -> {}", name, e);
+    }
+
     Custom(Status::Ok, "success".to_string())
 }
 
 #[rocket::get("/delete-img?<name>")]
-async fn delete_image(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
-    let mut path = PathBuf::from(LINUX_TYNKERBASE_PATH);
-    path.push(name);
+async fn delete_image(name: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
 
-    let img_name = format!("{}{IMAGE_MOD}", name);
+    let img_name = name.image_name();
     let res = docker_utils::delete_image(&img_name);
     if let Err(e) = res.await {
         return Custom(Status::InternalServerError, format!("Failed to delete image -> {}", e));
@@ -389,10 +1041,10 @@ async fn delete_image(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<Str
 }
 
 #[rocket::get("/list-imgs")]
-async fn list_images(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn list_images(apikey: ApiKey) -> Custom<String> {
     let lst = docker_utils::list_images().await;
     match lst {
-        Ok(l) => Custom(Status::Ok, l),
+        Ok(l) => Custom(Status::Ok, apikey.filter_docker_output(l)),
         Err(e) => Custom(
             Status::InternalServerError, 
             format!("Error getting images -> {}", e)
@@ -401,32 +1053,82 @@ async fn list_images(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
 }
 
 #[rocket::post("/spawn-container", data="<data>")]
-async fn spawn_container(data: Vec<u8>, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
-    let data: ProjConfig = bincode::deserialize(&data).unwrap();
+async fn spawn_container(data: Vec<u8>, apikey: ApiKey, audit: &AuditProject) -> Custom<String> {
+    if let Err(e) = apikey.verify_body(&data) {
+        return e;
+    }
+    let data: ProjConfig = match bincode::deserialize(&data) {
+        Ok(d) => d,
+        Err(e) => return Custom(Status::BadRequest, format!("Invalid project config -> {e}")),
+    };
+    let name = match parse_proj_name(&data.proj_name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    audit.set(name.as_str());
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+
+    let img_name = name.image_name();
+    let container_name = name.container_name();
 
-    let img_name = format!("{}{IMAGE_MOD}", &data.proj_name);
-    let container_name = format!("{}{CONTAINER_MOD}", &data.proj_name);
+    let lock = get_global().read().await;
+    let secrets = match &lock.tyb_apikey {
+        Some(k) => secret_utils::prepare_injection(&name, k),
+        None => Err(anyhow!("The node has no API key yet")),
+    };
+    drop(lock);
+    let secrets = match secrets {
+        Ok(s) => s,
+        Err(e) => {
+            return Custom(
+                Status::InternalServerError,
+                format!("Failed to load project secrets -> {e}"),
+            );
+        }
+    };
 
     let f = docker_utils::start_container(
         &img_name, 
         &container_name, 
         &data.port_mapping, 
-        &data.volume_mapping
+        &data.volume_mapping,
+        secrets.env_file.as_deref(),
+        &secrets.mounts,
     );
+    let res = f.await;
+    secrets.cleanup();
 
-    if let Err(e) = f.await {
+    if let Err(e) = res {
         return Custom(
             Status::InternalServerError,
             format!("Failed to start container -> {e}"),
         );
     }
 
+    let now = auth_utils::now_secs();
+    if let Err(e) = proj_meta_utils::update(&name, |m| {
+        m.last_spawn = Some(now);
+        m.last_config = Some(data);
+    }) {
+        log::warn!("Failed to update the metadata of project `{}` -> {}", name, e);
+    }
+
     Custom(Status::Ok, "success".to_string())
 }
 
 #[rocket::get("/pause-container?<name>")]
-async fn pause_container(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
-    let container_name = format!("{}{CONTAINER_MOD}", name);
+async fn pause_container(name: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+
+    let container_name = name.container_name();
     if let Err(e) = docker_utils::pause_container(&container_name).await {
         return Custom(
             Status::InternalServerError,
@@ -455,8 +1157,16 @@ async fn get_diags(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
 }
 
 #[rocket::get("/delete-container?<name>")]
-async fn delete_container(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom<String> {
-    let container_name = format!("{}{CONTAINER_MOD}", name);
+async fn delete_container(name: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+
+    let container_name = name.container_name();
     if let Err(e) = docker_utils::delete_container(&container_name).await {
         return Custom(
             Status::InternalServerError,
@@ -468,10 +1178,10 @@ async fn delete_container(name: &str, #[allow(unused)] apikey: ApiKey) -> Custom
 }
 
 #[rocket::get("/list-containers")]
-async fn list_containers(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn list_containers(apikey: ApiKey) -> Custom<String> {
     let lst = docker_utils::list_containers().await;
     match lst {
-        Ok(l) => Custom(Status::Ok, l),
+        Ok(l) => Custom(Status::Ok, apikey.filter_docker_output(l)),
         Err(e) => Custom(
             Status::InternalServerError, 
             format!("Error getting containers -> {}", e)
@@ -480,10 +1190,10 @@ async fn list_containers(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
 }
 
 #[rocket::get("/list-container-stats")]
-async fn list_container_stats(#[allow(unused)] apikey: ApiKey) -> Custom<String> {
+async fn list_container_stats(apikey: ApiKey) -> Custom<String> {
     let lst = docker_utils::list_container_stats().await;
     match lst {
-        Ok(l) => Custom(Status::Ok, l),
+        Ok(l) => Custom(Status::Ok, apikey.filter_docker_output(l)),
         Err(e) => Custom(
             Status::InternalServerError, 
             format!("Error getting containers -> {}", e)
@@ -497,112 +1207,735 @@ async fn identify(#[allow(unused)] apikey: ApiKey) -> String {
     gstate.read().await.node_id.clone().unwrap()
 }
 
-#[rocket::get("/")]
-async fn root() -> &'static str {
-    "alive"
-}
-
-#[rocket::catch(404)]
-fn handle_404(req: &Request) -> Custom<String> {
-    let body = format!("404: `{}` is not a valid path.", req.uri());
-    Custom(Status::NotFound, body)
-}
+#[rocket::get("/identity")]
+async fn node_identity(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize(Permission::ReadOnly, None) {
+        return e;
+    }
 
-fn get_global() -> &'static TsGlobalState {
-    GSTATE.get_or_init(|| GlobalState::new())
+    let identity = match node_utils::load_identity() {
+        Ok(Some(i)) => i,
+        Ok(None) => return Custom(Status::InternalServerError, "Node has no identity".to_string()),
+        Err(e) => return Custom(Status::InternalServerError, format!("Error loading node identity -> {e}")),
+    };
+    let lock = get_global().read().await;
+    let res = serde_json::json!({
+        "node_id": identity.node_id,
+        "name": identity.name,
+        "created_at": identity.created_at,
+        "renamed_at": identity.renamed_at,
+        "public_addr": lock.public_addr,
+        "offline": lock.offline,
+    });
+
+    Custom(Status::Ok, res.to_string())
 }
 
-static GSTATE: OnceLock<TsGlobalState> = OnceLock::new();
+#[rocket::get("/rename?<name>")]
+async fn rename_node(name: &str, apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+    if let Err(e) = node_utils::validate_node_name(name) {
+        return Custom(Status::BadRequest, e.to_string());
+    }
 
-#[launch]
-async fn rocket() -> _ {
-    // Ensure we're running on linux
-    #[cfg(not(debug_assertions))]
-    {
-        if OS != "linux" {
-            println!("Unfortunately, we only support linux at the current time.");
-            process::exit(0);
+    let gstate = get_global();
+    let lock = gstate.read().await;
+    if lock.offline {
+        return Custom(
+            Status::ServiceUnavailable,
+            "The control server is unreachable, can't check that the name is unique".to_string(),
+        );
+    }
+    if lock.name.as_deref() == Some(name) {
+        return Custom(Status::Ok, "success".to_string());
+    }
+    let email = lock.email.clone().unwrap_or_default();
+    let pass_sha256 = lock.pass_sha256.clone().unwrap_or_default();
+    drop(lock);
+
+    match control_plane::get().node_name_taken(&email, &pass_sha256, name).await {
+        Ok(false) => {}
+        Ok(true) => return Custom(Status::Conflict, format!("Node name `{}` already exists", name)),
+        Err(e) => return Custom(Status::BadGateway, format!("Failed to check node name -> {e}")),
+    }
+
+    let mut identity = match node_utils::load_identity() {
+        Ok(Some(i)) => i,
+        Ok(None) => return Custom(Status::InternalServerError, "Node has no identity".to_string()),
+        Err(e) => return Custom(Status::InternalServerError, format!("Error loading node identity -> {e}")),
+    };
+    identity.name = name.to_string();
+    identity.renamed_at = Some(auth_utils::now_secs());
+    if let Err(e) = node_utils::save_identity(&identity) {
+        return Custom(Status::InternalServerError, format!("Failed to save node identity -> {e}"));
+    }
+
+    let mut lock = gstate.write().await;
+    lock.name = Some(identity.name.clone());
+    if let Err(e) = session_utils::cache_session(&lock) {
+        log::warn!("Failed to update cached session -> {}", e);
+    }
+    let public_addr = lock.public_addr.clone();
+    drop(lock);
+
+    if let Some(addr) = public_addr {
+        let res = ngrok_utils::register_addr(&email, &pass_sha256, &identity.node_id, &identity.name, &addr).await;
+        if let Err(e) = res {
+            return Custom(
+                Status::BadGateway,
+                format!("Renamed the node but failed to re-register its address -> {e}"),
+            );
         }
     }
 
-    // Create TynkerBase Directory
-    #[cfg(not(debug_assertions))] {
-        let path = Path::new(LINUX_TYNKERBASE_PATH);
-        if !path.exists() {
-            if let Err(e) = fs::create_dir_all(path) {
-                if e.to_string().contains("Permission denied") {
-                    println!("TynkerBase Agent needs root privileges. Please re-run with `sudo`");
-                    std::process::exit(0);
-                }
-            }
+    Custom(Status::Ok, "success".to_string())
+}
+
+#[rocket::get("/create-token?<name>&<permission>&<projects>")]
+async fn create_token(
+    name: &str,
+    permission: &str,
+    projects: Option<&str>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let permission = match Permission::parse(permission) {
+        Ok(p) => p,
+        Err(e) => return Custom(Status::BadRequest, e.to_string()),
+    };
+    let projects = match projects.map(parse_proj_list).transpose() {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    let gstate = get_global();
+    let mut lock = gstate.write().await;
+    if lock.tokens.iter().any(|t| t.name == name) {
+        return Custom(Status::Conflict, format!("Token `{}` already exists", name));
+    }
+
+    let (token, record) = match auth_utils::gen_token(name, permission, projects) {
+        Ok(r) => r,
+        Err(e) => return Custom(Status::BadRequest, e.to_string()),
+    };
+    lock.tokens.push(record);
+
+    if let Err(e) = auth_utils::save_tokens(&lock.tokens) {
+        lock.tokens.pop();
+        return Custom(Status::InternalServerError, format!("Failed to save token -> {e}"));
+    }
+
+    Custom(Status::Ok, token)
+}
+
+#[rocket::get("/list-tokens")]
+async fn list_tokens(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let gstate = get_global();
+    let lock = gstate.read().await;
+    let tokens: Vec<Identity> = lock.tokens.iter().map(Identity::from).collect();
+
+    match serde_json::to_string(&tokens) {
+        Ok(json) => Custom(Status::Ok, json),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing tokens: {:?}", e)),
+    }
+}
+
+#[rocket::get("/revoke-token?<name>")]
+async fn revoke_token(name: &str, apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let gstate = get_global();
+    let mut lock = gstate.write().await;
+    let before = lock.tokens.len();
+    lock.tokens.retain(|t| t.name != name);
+    if lock.tokens.len() == before {
+        return Custom(Status::NotFound, format!("Token `{}` does not exist", name));
+    }
+
+    if let Err(e) = auth_utils::save_tokens(&lock.tokens) {
+        return Custom(Status::InternalServerError, format!("Failed to save tokens -> {e}"));
+    }
+
+    Custom(Status::Ok, "success".to_string())
+}
+
+#[rocket::post("/rotate-key?<grace_secs>")]
+async fn rotate_key(grace_secs: Option<u64>, apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+    let grace = Duration::from_secs(grace_secs.unwrap_or(DEFAULT_KEY_GRACE_SECS));
+
+    let gstate = get_global();
+    let lock = gstate.read().await;
+    if lock.offline {
+        return Custom(
+            Status::ServiceUnavailable,
+            "Cannot rotate the API key while the control server is unreachable".to_string(),
+        );
+    }
+    let email = lock.email.clone().unwrap();
+    let pass_sha256 = lock.pass_sha256.clone().unwrap();
+    let pass_sha384 = lock.pass_sha384.clone().unwrap();
+    let old_key = lock.tyb_apikey.clone().unwrap();
+    let public = lock.public_addr.is_some();
+    drop(lock);
+
+    // Everything that can fail without changing the control server's state is done before the
+    // salt is rotated, so those failures leave the node and the control server as they were
+    if let Err(e) = secret_utils::decrypt_all(&old_key) {
+        return Custom(Status::InternalServerError, format!("Failed to decrypt secrets -> {e}"));
+    }
+    // The ngrok token is stored encrypted with the API key, so it's re-encrypted with the new one
+    let ngrok_token = match public {
+        true => ngrok_utils::get_token(&email, &pass_sha256, &old_key).await,
+        false => None,
+    };
+
+    let salt = match control_plane::get().rotate_salt(&email, &pass_sha256).await {
+        Ok(s) => s,
+        Err(e) => return Custom(Status::BadGateway, format!("Failed to rotate salt -> {e}")),
+    };
+    let new_key = crypt_utils::gen_apikey(&pass_sha384, &salt);
+
+    // From here on the control server derives the new key, so the node follows it
+    if let Some(tok) = ngrok_token {
+        if let Err(e) = ngrok_utils::store_token(&email, &pass_sha256, &new_key, tok).await {
+            log::warn!("Failed to re-encrypt ngrok token after key rotation -> {}", e);
+        }
+    }
+
+    let mut lock = gstate.write().await;
+    // Secrets are encrypted with the API key. The write lock keeps new secrets from being stored
+    // with the old key while they're re-encrypted. If they can't be, the node keeps the old key
+    // and re-syncs with the control server in the background like after an outage, which moves
+    // the secrets over once it can.
+    let res = secret_utils::decrypt_all(&old_key).and_then(|staged| staged.commit(&new_key));
+    if let Err(e) = res {
+        log::error!("Failed to re-encrypt secrets, keeping the old API key until they are -> {}", e);
+        lock.offline = true;
+        tokio::spawn(session_utils::resync_loop(gstate, public));
+        return Custom(Status::InternalServerError, format!("Failed to re-encrypt secrets -> {e}"));
+    }
+    lock.prev_apikey = Some((old_key, Instant::now() + grace));
+    lock.tyb_apikey = Some(new_key.clone());
+
+    if let Some(session_key) = &lock.session_key {
+        let session = CachedSession {
+            email,
+            pass_sha256,
+            pass_sha384,
+            tyb_apikey: new_key.clone(),
+            node_id: lock.node_id.clone().unwrap(),
+            name: lock.name.clone().unwrap(),
+        };
+        if let Err(e) = session_utils::save_session(&session, session_key) {
+            log::warn!("Failed to update cached session after key rotation -> {}", e);
         }
     }
 
-    // load global state
+    Custom(Status::Ok, new_key)
+}
+
+#[rocket::get("/list-bans")]
+async fn list_bans(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let gstate = get_global();
+    let bans = gstate.read().await.bans.list_bans();
+
+    match serde_json::to_string(&bans) {
+        Ok(json) => Custom(Status::Ok, json),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing bans: {:?}", e)),
+    }
+}
+
+#[rocket::get("/clear-bans?<ip>")]
+async fn clear_bans(ip: Option<&str>, apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let ip = match ip.map(|ip| ip.parse::<IpAddr>()) {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(e)) => return Custom(Status::BadRequest, format!("Invalid IP address -> {e}")),
+        None => None,
+    };
+
+    let gstate = get_global();
+    let cleared = gstate.write().await.bans.clear(ip.as_ref());
+
+    Custom(Status::Ok, cleared.to_string())
+}
+
+#[rocket::get("/issue-client-cert?<name>&<permission>&<projects>")]
+async fn issue_client_cert(
+    name: &str,
+    permission: &str,
+    projects: Option<&str>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+    if let Err(e) = auth_utils::validate_identity_name(name) {
+        return Custom(Status::BadRequest, e.to_string());
+    }
+    let permission = match Permission::parse(permission) {
+        Ok(p) => p,
+        Err(e) => return Custom(Status::BadRequest, e.to_string()),
+    };
+    let projects = match projects.map(parse_proj_list).transpose() {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
+
+    let gstate = get_global();
+    let mut lock = gstate.write().await;
+    if lock.client_certs.iter().any(|c| c.name == name && !c.revoked) {
+        return Custom(Status::Conflict, format!("Client certificate `{}` already exists", name));
+    }
+    if !tls_utils::check_client_ca() {
+        return Custom(
+            Status::Conflict,
+            "Client CA does not exist, restart the agent with `--priv --mtls` to create it".to_string(),
+        );
+    }
+
+    let issued = match tls_utils::issue_client_cert(name) {
+        Ok(i) => i,
+        Err(e) => return Custom(Status::InternalServerError, format!("Failed to issue certificate -> {e}")),
+    };
+    let ca_pem = fs::read_to_string(&tls_utils::get_client_ca_paths()[0]).unwrap_or_default();
+
+    lock.client_certs.push(auth_utils::ClientCert {
+        name: name.to_string(),
+        serial: issued.serial.clone(),
+        permission,
+        projects,
+        created_at: auth_utils::now_secs(),
+        revoked: false,
+    });
+    if let Err(e) = auth_utils::save_client_certs(&lock.client_certs) {
+        lock.client_certs.pop();
+        return Custom(Status::InternalServerError, format!("Failed to save client certificate -> {e}"));
+    }
+
+    let body = serde_json::json!({
+        "name": name,
+        "serial": issued.serial,
+        "cert_pem": issued.cert_pem,
+        "key_pem": issued.key_pem,
+        "ca_pem": ca_pem,
+    });
+    Custom(Status::Ok, body.to_string())
+}
+
+#[rocket::get("/list-client-certs")]
+async fn list_client_certs(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let gstate = get_global();
+    let lock = gstate.read().await;
+
+    match serde_json::to_string(&lock.client_certs) {
+        Ok(json) => Custom(Status::Ok, json),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing client certificates: {:?}", e)),
+    }
+}
+
+#[rocket::get("/revoke-client-cert?<name>")]
+async fn revoke_client_cert(name: &str, apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let gstate = get_global();
+    let mut lock = gstate.write().await;
+    let cert = match lock.client_certs.iter_mut().find(|c| c.name == name && !c.revoked) {
+        Some(c) => c,
+        None => return Custom(Status::NotFound, format!("Client certificate `{}` does not exist", name)),
+    };
+    cert.revoked = true;
+
+    if let Err(e) = auth_utils::save_client_certs(&lock.client_certs) {
+        return Custom(Status::InternalServerError, format!("Failed to save client certificates -> {e}"));
+    }
+
+    Custom(Status::Ok, "success".to_string())
+}
+
+#[rocket::post("/set?<name>&<secret>&<as_file>", data = "<data>")]
+async fn set_secret(
+    name: &str,
+    secret: &str,
+    as_file: Option<bool>,
+    data: Vec<u8>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    if let Err(e) = apikey.verify_body(&data) {
+        return e;
+    }
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+
+    let value = match String::from_utf8(data) {
+        Ok(v) => v,
+        Err(_) => return Custom(Status::BadRequest, "Secret value must be valid UTF-8".to_string()),
+    };
+
+    // Held until the secret is stored so a key rotation can't happen in between
+    let lock = get_global().read().await;
+    let node_apikey = match &lock.tyb_apikey {
+        Some(k) => k,
+        None => return Custom(Status::ServiceUnavailable, "The node has no API key yet".to_string()),
+    };
+    if let Err(e) = secret_utils::set_secret(&name, secret, &value, as_file.unwrap_or(false), node_apikey) {
+        return Custom(Status::BadRequest, format!("Failed to store secret -> {e}"));
+    }
+
+    Custom(Status::Ok, "success".to_string())
+}
+
+#[rocket::get("/list?<name>")]
+async fn list_secrets(name: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::ReadOnly, Some(name.as_str())) {
+        return e;
+    }
+
+    let secrets = match secret_utils::list_secrets(&name) {
+        Ok(s) => s,
+        Err(e) => return Custom(Status::InternalServerError, format!("Error reading secrets -> {e}")),
+    };
+
+    match serde_json::to_string(&secrets) {
+        Ok(json) => Custom(Status::Ok, json),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing secrets: {:?}", e)),
+    }
+}
+
+#[rocket::get("/delete?<name>&<secret>")]
+async fn delete_secret(name: &str, secret: &str, apikey: ApiKey) -> Custom<String> {
+    let name = match parse_proj_name(name) {
+        Ok(n) => n,
+        Err(e) => return e,
+    };
+    if let Err(e) = apikey.authorize(Permission::Deploy, Some(name.as_str())) {
+        return e;
+    }
+
+    if let Err(e) = secret_utils::delete_secret(&name, secret) {
+        if e.to_string().contains("does not exist") {
+            return Custom(Status::NotFound, e.to_string());
+        }
+        return Custom(Status::InternalServerError, format!("Failed to delete secret -> {e}"));
+    }
+
+    Custom(Status::Ok, "success".to_string())
+}
+
+#[rocket::get("/query?<since>&<until>&<project>")]
+async fn query_audit_log(
+    since: Option<u64>,
+    until: Option<u64>,
+    project: Option<&str>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    let auth = match project {
+        Some(p) => apikey.authorize(Permission::Admin, Some(p)),
+        None => apikey.authorize_node_wide(Permission::Admin),
+    };
+    if let Err(e) = auth {
+        return e;
+    }
+
+    let entries = match audit_utils::query(since, until, project) {
+        Ok(e) => e,
+        Err(e) => return Custom(Status::InternalServerError, format!("Error reading audit log -> {e}")),
+    };
+
+    match serde_json::to_string(&entries) {
+        Ok(json) => Custom(Status::Ok, json),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing audit log: {:?}", e)),
+    }
+}
+
+#[rocket::get("/tail?<lines>&<level>&<since_ms>&<contains>&<request_id>")]
+async fn tail_logs(
+    lines: Option<usize>,
+    level: Option<&str>,
+    since_ms: Option<u64>,
+    contains: Option<&str>,
+    request_id: Option<&str>,
+    apikey: ApiKey,
+) -> Custom<String> {
+    if let Err(e) = apikey.authorize_node_wide(Permission::Admin) {
+        return e;
+    }
+
+    let min_level = match level.map(log::Level::from_str) {
+        None => None,
+        Some(Ok(l)) => Some(l),
+        Some(Err(_)) => {
+            return Custom(
+                Status::BadRequest,
+                format!("`{}` is not a log level, use error, warn, info, debug or trace", level.unwrap_or_default()),
+            )
+        }
+    };
+    let filter = log_utils::LogQuery {
+        min_level,
+        since_ms,
+        contains,
+        request_id,
+    };
+    let limit = lines.unwrap_or(DEFAULT_TAIL_LINES).min(MAX_TAIL_LINES);
+
+    let records = match log_utils::query(&filter, limit) {
+        Ok(r) => r,
+        Err(e) => return Custom(Status::InternalServerError, format!("Error reading agent log -> {e}")),
+    };
+
+    match serde_json::to_string(&records) {
+        Ok(json) => Custom(Status::Ok, json),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing agent log: {:?}", e)),
+    }
+}
+
+#[rocket::get("/")]
+async fn root() -> &'static str {
+    "alive"
+}
+
+fn health_response(report: health_utils::HealthReport) -> Custom<String> {
+    let status = Status::from_code(report.http_status()).unwrap_or(Status::ServiceUnavailable);
+    match serde_json::to_string(&report) {
+        Ok(json) => Custom(status, json),
+        Err(e) => Custom(Status::InternalServerError, format!("Error serializing health report: {:?}", e)),
+    }
+}
+
+// Readiness for load balancers and monitors, see `health_utils`. Unlike `/` it fails when
+// the node can't actually run projects.
+#[rocket::get("/health")]
+async fn health() -> Custom<String> {
+    health_response(health_utils::check_public(get_global()).await)
+}
+
+#[rocket::get("/health/details")]
+async fn health_details(apikey: ApiKey) -> Custom<String> {
+    if let Err(e) = apikey.authorize(Permission::ReadOnly, None) {
+        return e;
+    }
+    health_response(health_utils::check(get_global(), true).await)
+}
+
+#[rocket::catch(404)]
+fn handle_404(req: &Request) -> Custom<String> {
+    let body = format!("404: `{}` is not a valid path.", req.uri());
+    Custom(Status::NotFound, body)
+}
+
+fn get_global() -> &'static TsGlobalState {
+    GSTATE.get_or_init(GlobalState::new)
+}
+
+static GSTATE: OnceLock<TsGlobalState> = OnceLock::new();
+
+// How long the old key keeps working after a rotation unless the caller asks otherwise
+const DEFAULT_KEY_GRACE_SECS: u64 = 60 * 60;
+// Records returned by `/logs/tail` when no count is given, and the most it returns
+const DEFAULT_TAIL_LINES: usize = 200;
+const MAX_TAIL_LINES: usize = 5000;
+
+// Handlers that change state on the node, every call to them is written to the audit log
+const AUDITED_ROUTES: &[&str] = &[
+    "create_proj",
+    "add_files_to_proj",
+    "apply_proj_diff",
+    "start_upload",
+    "put_upload_chunk",
+    "finalize_upload",
+    "abort_upload",
+    "restore_snapshot",
+    "prune_snapshots",
+    "delete_proj",
+    "purge_projects",
+    "start_docker_daemon",
+    "end_docker_daemon",
+    "build_image",
+    "delete_image",
+    "spawn_container",
+    "pause_container",
+    "delete_container",
+    "create_token",
+    "revoke_token",
+    "rotate_key",
+    "rename_node",
+    "clear_bans",
+    "issue_client_cert",
+    "revoke_client_cert",
+    "set_secret",
+    "delete_secret",
+];
+
+// Logs in with the control server, or starts from the cached session when it can't be reached,
+// and fills in the global state. Returns whether the agent is offline.
+async fn log_in(inputs: &StartupInputs) -> bool {
     let gstate = get_global();
 
     // Get login info
-    let email = crypt_utils::prompt("Enter your email: ");
-    let password = crypt_utils::prompt_secret("Enter your password: ");
+    let login = inputs
+        .require(Input::Email, "Enter your email: ")
+        .and_then(|email| Ok((email, inputs.require(Input::Password, "Enter your password: ")?)));
+    let (email, password) = match login {
+        Ok(l) => l,
+        Err(e) => {
+            log::error!("Unable to get login info -> {}", e);
+            process::exit(1);
+        }
+    };
     let pass_sha256 = hash_utils::sha256(&password);
     let pass_sha384 = hash_utils::sha384(&password);
 
-    // Authorize login info
-    let endpoint = format!(
-        "{}/auth/login?email={}&pass_sha256={}",
-        SERVER_ENDPOINT, email, pass_sha256
-    );
-    let res = reqwest::get(&endpoint).await.unwrap();
-    if res.status().as_u16() == 403 {
-        println!("Incorrect authorization.");
-        process::exit(0);
-    }
+    let session_key = session_utils::derive_session_key(&password);
 
+    // Authorize login info, falling back to the cached session if the server can't be reached
     let mut lock = gstate.write().await;
-    lock.tyb_apikey = Some(load_apikey(res, &pass_sha384).await);
-    let (node_id, name) = load_node_info(&email, &pass_sha256).await;
-    lock.node_id = Some(node_id);
-    lock.name = Some(name);
+    match control_plane::get().login(&email, &pass_sha256).await {
+        LoginResult::Success(salt) => {
+            let tyb_apikey = crypt_utils::gen_apikey(&pass_sha384, &salt);
+            let (node_id, name) = load_node_info(&email, &pass_sha256, inputs).await;
+
+            // Secrets are encrypted with the API key, follow it if it changed since the last start
+            if let Ok(prev) = session_utils::load_session(&session_key) {
+                if prev.tyb_apikey != tyb_apikey {
+                    if let Err(e) = secret_utils::reencrypt_all(&prev.tyb_apikey, &tyb_apikey) {
+                        log::error!("Failed to re-encrypt secrets for the new API key -> {}", e);
+                    }
+                }
+            }
+
+            let session = CachedSession {
+                email: email.clone(),
+                pass_sha256: pass_sha256.clone(),
+                pass_sha384: pass_sha384.clone(),
+                tyb_apikey: tyb_apikey.clone(),
+                node_id: node_id.clone(),
+                name: name.clone(),
+            };
+            if let Err(e) = session_utils::save_session(&session, &session_key) {
+                log::warn!("Failed to cache session for offline startup -> {}", e);
+            }
+
+            lock.tyb_apikey = Some(tyb_apikey);
+            lock.node_id = Some(node_id);
+            lock.name = Some(name);
+        }
+        LoginResult::Unauthorized => {
+            log::error!("Incorrect authorization.");
+            process::exit(if inputs.is_interactive() { 0 } else { 1 });
+        }
+        LoginResult::Unreachable(e) => {
+            log::warn!("Unable to reach the control server -> {}", e);
+            let session = match session_utils::load_session(&session_key) {
+                Ok(s) if s.email == email && s.pass_sha256 == pass_sha256 => s,
+                Ok(_) => {
+                    log::error!("The cached session belongs to a different account.");
+                    process::exit(1);
+                }
+                Err(e) => {
+                    log::error!("Unable to start offline -> {}", e);
+                    process::exit(1);
+                }
+            };
+            log::warn!("Starting from the cached session, will re-sync once the control server is reachable.");
+
+            lock.tyb_apikey = Some(session.tyb_apikey);
+            lock.node_id = Some(session.node_id);
+            lock.name = Some(session.name);
+            lock.offline = true;
+        }
+    }
 
     lock.email = Some(email);
     lock.pass_sha256 = Some(pass_sha256);
     lock.pass_sha384 = Some(pass_sha384);
+    lock.session_key = Some(session_key);
+    lock.tokens = auth_utils::load_tokens();
+    lock.client_certs = auth_utils::load_client_certs();
 
+    let offline = lock.offline;
     drop(lock);
     drop(password);
 
-    // Ensure TLS keys and certificates are ready
-    let root_dir = consts::AGENT_ROOTDIR_PATH;
-    if !tls_utils::check_tls_cert(root_dir) {
-        if !dep_utils::openssl::check_openssl() {
-            println!("In order to enable TLS encryption, you need to install open ssl. (If it's already installed, try restarting the terminal)");
-            let res = crypt_utils::prompt("Would you like to do that now? (y/n): ");
-            if res.to_ascii_lowercase() == "y" {
-                if let Err(e) = dep_utils::openssl::install_openssl() {
-                    println!(
-                        "Failed to install OpenSSL, install manually.\nError -> {}",
-                        e
-                    );
-                    process::exit(1);
-                } else {
-                    println!("Successfully installed OpenSSL!");
-                }
-            }
-            process::exit(0);
+    offline
+}
+
+// Generates a self signed TLS certificate, offering to install openssl first if it's missing
+fn gen_cert(inputs: &StartupInputs) {
+    let root_dir = config_utils::root_dir();
+    if !dep_utils::openssl::check_openssl() {
+        println!("In order to enable TLS encryption, you need to install open ssl. (If it's already installed, try restarting the terminal)");
+        let res = inputs.confirm("Would you like to do that now? (y/n): ");
+        if let Err(e) = &res {
+            println!("Error: {}", e);
+            process::exit(1);
         }
-        if let Err(e) = tls_utils::gen_tls_cert(root_dir) {
-            println!("Error:\n{}", e);
-            std::process::exit(1);
+        if let Ok(true) = res {
+            if let Err(e) = dep_utils::openssl::install_openssl() {
+                println!(
+                    "Failed to install OpenSSL, install manually.\nError -> {}",
+                    e
+                );
+                process::exit(1);
+            } else {
+                println!("Successfully installed OpenSSL!");
+            }
         }
+        process::exit(0);
+    }
+    if let Err(e) = tls_utils::gen_tls_cert(root_dir, inputs.is_interactive()) {
+        println!("Error:\n{}", e);
+        std::process::exit(1);
     }
+}
 
-    // Ensure docker is installed
+fn ensure_docker(inputs: &StartupInputs) {
     if !dep_utils::docker::check_docker() {
         println!(
             "Docker is not installed. (If it's already installed, try restarting the terminal)"
         );
-        let res = crypt_utils::prompt("Install docker now? (y/n): ");
-        if res.to_ascii_lowercase() == "y" {
+        let res = inputs.confirm("Install docker now? (y/n): ");
+        if let Err(e) = &res {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+        if let Ok(true) = res {
             if let Err(e) = dep_utils::docker::install_docker() {
                 println!(
                     "Failed to install Docker, install manually.\nError -> {}",
@@ -615,10 +1948,78 @@ async fn rocket() -> _ {
         }
         process::exit(0);
     }
+}
+
+async fn run(inputs: &StartupInputs) {
+    let config = config_utils::get();
+    let gstate = get_global();
+    log_utils::init();
+    tokio::spawn(systemd_utils::watchdog_loop(gstate));
+
+    if let Err(e) = proj_meta_utils::adopt_existing_projects() {
+        log::warn!("Failed to write metadata for existing projects -> {}", e);
+    }
+
+    systemd_utils::notify_status("Logging in");
+    let offline = log_in(inputs).await;
+
+    // Ensure TLS keys and certificates are ready
+    if !tls_utils::uses_custom_cert() && !tls_utils::check_tls_cert(config_utils::root_dir()) {
+        gen_cert(inputs);
+    }
+
+    // Ensure docker is installed
+    ensure_docker(inputs);
+
+    let private = inputs.has_switch("--priv") || !config.tunnel.enabled;
+    let mutual_tls = private && inputs.has_switch("--mtls");
+
+    // Ensure the client CA (and a first admin certificate) exist for mutual TLS
+    if mutual_tls {
+        if !tls_utils::check_client_ca() {
+            if let Err(e) = tls_utils::gen_client_ca() {
+                println!("Error generating client CA:\n{}", e);
+                process::exit(1);
+            }
+        }
+        let mut lock = gstate.write().await;
+        if lock.client_certs.iter().all(|c| c.revoked) {
+            let issued = match tls_utils::issue_client_cert("admin") {
+                Ok(i) => i,
+                Err(e) => {
+                    println!("Error issuing admin client certificate:\n{}", e);
+                    process::exit(1);
+                }
+            };
+            let dir = format!("{}/keys/clients", config_utils::root_dir());
+            let written = fs::create_dir_all(&dir)
+                .and_then(|_| fs::write(format!("{dir}/admin-cert.pem"), &issued.cert_pem))
+                .and_then(|_| fs::write(format!("{dir}/admin-key.pem"), &issued.key_pem));
+            if let Err(e) = written {
+                println!("Error saving admin client certificate:\n{}", e);
+                process::exit(1);
+            }
+            lock.client_certs.push(auth_utils::ClientCert {
+                name: "admin".to_string(),
+                serial: issued.serial,
+                permission: Permission::Admin,
+                projects: None,
+                created_at: auth_utils::now_secs(),
+                revoked: false,
+            });
+            if let Err(e) = auth_utils::save_client_certs(&lock.client_certs) {
+                println!("Error saving client certificates:\n{}", e);
+                process::exit(1);
+            }
+            println!(
+                "Issued an admin client certificate to `{dir}`. Copy it to your client and delete the key from this node."
+            );
+        }
+    }
 
     // Ensure ngrok auth token is ready
-    let envs = env::args().collect::<Vec<String>>();
-    if !(envs.len() >= 2 && envs[1] == "--priv") {
+    if !private {
+        systemd_utils::notify_status("Starting tunnel");
         let lock = gstate.read().await;
 
         let email = lock.email.clone().unwrap();
@@ -646,7 +2047,8 @@ async fn rocket() -> _ {
         let query = f_query.await;
         // let installed = f_installed.await;
 
-        let attach_tok = true;
+        // While offline we rely on the token ngrok already has in its config
+        let attach_tok = !offline;
         // if let Ok(Ok(b)) = installed {
         //     attach_tok = !b;
         // }
@@ -667,7 +2069,18 @@ async fn rocket() -> _ {
                 );
                 prompt.push_str("\nPlease enter that auth token here: ");
 
-                let tok = crypt_utils::prompt_secret(&prompt);
+                // Flags, env vars and the credentials file take precedence over the config file
+                let tok = match (inputs.get(Input::NgrokToken), &config.tunnel.authtoken) {
+                    (Ok(None), Some(t)) => Ok(t.clone()),
+                    _ => inputs.require(Input::NgrokToken, &prompt),
+                };
+                let tok = match tok {
+                    Ok(t) => t,
+                    Err(e) => {
+                        println!("Error: no ngrok auth token found (get one at {}) -> {}", url, e);
+                        process::exit(1);
+                    }
+                };
                 let f = tokio::spawn(ngrok_utils::attach_token(tok.clone()));
                 let f_mong = tokio::spawn(ngrok_utils::store_token(
                     email.clone(),
@@ -681,43 +2094,93 @@ async fn rocket() -> _ {
             }
         }
 
-        let public_addr = ngrok_utils::make_public(&email, &pass_sha256, &node_id, &name)
-            .await
-            .unwrap();
+        let public_addr = if offline {
+            ngrok_utils::spawn_ngrok(config.tunnel.startup_timeout_secs).await
+        } else {
+            ngrok_utils::make_public(&email, &pass_sha256, &node_id, &name).await
+        };
+        let public_addr = public_addr.unwrap();
         println!("TynkerBase Agent running publicly on: {}", &public_addr);
+        gstate.write().await.public_addr = Some(public_addr);
     }
 
+    if offline {
+        tokio::spawn(session_utils::resync_loop(gstate, !private));
+    }
+    tokio::spawn(heartbeat_utils::heartbeat_loop(gstate));
+
     // Specify configuration
     let tls_paths = tls_utils::get_cert_paths();
-    let config = Config {
-        address: "0.0.0.0".parse().expect("Invalid address"),
-        port: 7462,
-        tls: Some(TlsConfig::from_paths(&tls_paths[0], &tls_paths[1])),
-        limits: Limits::default().limit("bytes", 20.megabytes()),
+    let mut tls_config = TlsConfig::from_paths(&tls_paths[0], &tls_paths[1]);
+    if mutual_tls {
+        let ca_path = &tls_utils::get_client_ca_paths()[0];
+        tls_config = tls_config.with_mutual(MutualTls::from_path(ca_path).mandatory(true));
+    }
+    let rocket_config = Config {
+        address: config.server.address.parse().expect("Invalid address"),
+        port: config.server.port,
+        tls: Some(tls_config),
+        limits: Limits::default().limit("bytes", config.server.upload_limit_mb.megabytes()),
         ..Config::default()
     };
-    let figment = Figment::from(config);
+    let figment = Figment::from(rocket_config);
 
     // Ensure all fields are filled before hosting
     let lock = gstate.read().await;
     assert!(lock.check_status());
     drop(lock);
 
-    rocket::custom(figment)
+    let res = rocket::custom(figment)
+        .attach(log_utils::RequestLogger)
+        .attach(AuditFairing { routes: AUDITED_ROUTES })
+        .attach(AdHoc::on_liftoff("systemd readiness", |rocket| Box::pin(async move {
+            let config = rocket.config();
+            log::info!("Agent listening on {}:{}", config.address, config.port);
+            systemd_utils::notify_status("Running");
+            systemd_utils::notify_ready();
+        })))
+        .attach(AdHoc::on_shutdown("Graceful shutdown", move |_| {
+            Box::pin(shutdown_utils::shutdown(gstate))
+        }))
         .register("/", catchers![handle_404])
-        .mount("/", routes![root, identify])
+        .mount("/", routes![root, health, health_details, identify])
         .mount("/diags", routes![get_diags])
+        .mount("/audit", routes![query_audit_log])
+        .mount("/logs", routes![tail_logs])
+        .mount("/node", routes![node_identity, rename_node])
+        .mount("/auth", routes![
+                create_token,
+                list_tokens,
+                revoke_token,
+                rotate_key,
+                list_bans,
+                clear_bans,
+                issue_client_cert,
+                list_client_certs,
+                revoke_client_cert,
+            ],)
         .mount(
             "/files/proj",
             routes![
                 create_proj,
                 add_files_to_proj,
+                proj_manifest,
+                apply_proj_diff,
                 delete_proj,
                 pull_proj_files,
                 list_projects,
                 purge_projects,
             ],
         )
+        .mount(
+            "/files/upload",
+            routes![start_upload, put_upload_chunk, upload_status, finalize_upload, abort_upload],
+        )
+        .mount(
+            "/files/snapshots",
+            routes![list_snapshots, restore_snapshot, prune_snapshots],
+        )
+        .mount("/secrets", routes![set_secret, list_secrets, delete_secret])
         .mount(
             "/docker/daemon",
             routes![start_docker_daemon, end_docker_daemon, get_daemon_status,],
@@ -734,5 +2197,215 @@ async fn rocket() -> _ {
                 list_containers,
                 list_container_stats,
             ],
+        );
+    if let Err(e) = res.launch().await {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[rocket::main]
+async fn main() {
+    // Ensure we're running on linux
+    #[cfg(not(debug_assertions))]
+    {
+        if OS != "linux" {
+            println!("Unfortunately, we only support linux at the current time.");
+            process::exit(0);
+        }
+    }
+
+    let inputs = match StartupInputs::from_env() {
+        Ok(i) => i,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let command = match Command::parse(inputs.positionals()) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    if command == Command::Help {
+        print!("{}", cli_utils::USAGE);
+        return;
+    }
+
+    // Load and validate the config before anything touches the paths in it
+    let config = match AgentConfig::load(inputs.flag("--config"), inputs.flag("--instance")) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    if command == Command::ConfigShow {
+        print!("{}", config.to_redacted_string());
+        return;
+    }
+    config_utils::init(config);
+    match control_plane::from_config() {
+        Ok(plane) => control_plane::init(plane),
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+
+    // Create TynkerBase Directory
+    #[cfg(not(debug_assertions))] {
+        let path = Path::new(config_utils::projects_dir());
+        if !path.exists() {
+            if let Err(e) = fs::create_dir_all(path) {
+                if e.to_string().contains("Permission denied") {
+                    println!("TynkerBase Agent needs root privileges. Please re-run with `sudo`");
+                    std::process::exit(0);
+                }
+            }
+        }
+    }
+
+    let res = match command {
+        Command::Run => {
+            run(&inputs).await;
+            Ok(())
+        }
+        Command::Login => {
+            if log_in(&inputs).await {
+                println!("Error: the control server is unreachable, only the cached session could be checked.");
+                process::exit(1);
+            }
+            let lock = get_global().read().await;
+            println!(
+                "Logged in as `{}`, this node is `{}` ({}).",
+                lock.email.clone().unwrap_or_default(),
+                lock.name.clone().unwrap_or_default(),
+                lock.node_id.clone().unwrap_or_default(),
+            );
+            Ok(())
+        }
+        Command::Logout => cli_utils::logout(),
+        Command::ResetNode => cli_utils::reset_node(&inputs),
+        Command::GenCert => {
+            if tls_utils::uses_custom_cert() {
+                Err(anyhow!("A certificate is configured in the [tls] section of the config, replace those files instead"))
+            } else {
+                let root_dir = config_utils::root_dir();
+                let replace = !tls_utils::check_tls_cert(root_dir)
+                    || inputs.confirm("A TLS certificate already exists, replace it? (y/n): ").unwrap_or_else(|e| {
+                        println!("Error: {}", e);
+                        process::exit(1);
+                    });
+                if replace {
+                    gen_cert(&inputs);
+                }
+                Ok(())
+            }
+        }
+        Command::Status => {
+            cli_utils::status().await;
+            Ok(())
+        }
+        Command::NodeShow => cli_utils::show_node(),
+        Command::NodeRename(name) => cli_utils::rename_node(&inputs, &name).await,
+        Command::InstallService => systemd_utils::render_unit(&inputs).and_then(|unit| {
+            if inputs.has_switch("--print") {
+                print!("{}", unit);
+                return Ok(());
+            }
+            let path = systemd_utils::install_unit(&unit)?;
+            println!("Installed `{}` and started the agent.", path);
+            println!("Follow its logs with `journalctl -u {} -f`.", systemd_utils::unit_name());
+            Ok(())
+        }),
+        Command::Doctor => {
+            if !cli_utils::doctor().await {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Command::ConfigShow | Command::Help => Ok(()),
+    };
+
+    if let Err(e) = res {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config_utils::ControlPlaneKind;
+    use control_plane::{ControlPlane, LocalControlPlane};
+    use rand::{distributions::Alphanumeric, thread_rng, Rng};
+    use tynkerbase_universal::netwk_utils::Node;
+
+    // Logs in against the local control plane the way a headless start would, registers the
+    // node like the tunnel does, and checks what ends up in `data/control-plane.json`
+    #[tokio::test]
+    async fn log_in_registers_node_with_local_control_plane() {
+        let suffix: String = thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect();
+        let root = std::env::temp_dir().join(format!("tyb-agent-test-{suffix}"));
+        fs::create_dir_all(&root).unwrap();
+        let root_dir = root.to_str().unwrap().to_string();
+
+        let mut config = AgentConfig::default();
+        config.paths.root_dir = root_dir.clone();
+        config.paths.projects_dir = format!("{root_dir}/projects");
+        config.control_server.kind = ControlPlaneKind::Local;
+        config_utils::init(config);
+        control_plane::init(control_plane::from_config().unwrap());
+
+        let (email, password, node_name) = ("test@example.com", "hunter22", "test-node");
+        let cred_path = format!("{root_dir}/credentials");
+        fs::write(
+            &cred_path,
+            format!("email = {email}\npassword = {password}\nnode_name = {node_name}\n"),
         )
+        .unwrap();
+        let args = ["--credentials", &cred_path, "--non-interactive"];
+        let inputs = StartupInputs::from_args(args.iter().map(|a| a.to_string()).collect()).unwrap();
+
+        assert!(!log_in(&inputs).await, "a local control plane is always reachable");
+
+        let pass_sha256 = hash_utils::sha256(password);
+        let (node_id, tyb_apikey) = {
+            let lock = get_global().read().await;
+            assert_eq!(lock.name.as_deref(), Some(node_name));
+            (lock.node_id.clone().unwrap(), lock.tyb_apikey.clone().unwrap())
+        };
+        assert!(!tyb_apikey.is_empty());
+
+        let node = Node {
+            email: email.to_string(),
+            node_id: node_id.clone(),
+            name: node_name.to_string(),
+            addr: "https://example.ngrok.app".to_string(),
+        };
+        control_plane::get().add_node_addr(email, &pass_sha256, &node).await.unwrap();
+
+        let path = format!("{root_dir}/data/control-plane.json");
+        let state: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let account = &state[email];
+        assert_eq!(account["pass_sha256"], pass_sha256);
+        let salt = account["salt"].as_str().unwrap().to_string();
+        assert!(!salt.is_empty());
+        assert_eq!(account["nodes"][&node_id]["name"], node_name);
+        assert_eq!(account["nodes"][&node_id]["addr"], node.addr);
+
+        // A restarted agent sees the same account, salt and node
+        let reopened = LocalControlPlane::open(&path).unwrap();
+        match reopened.login(email, &pass_sha256).await {
+            LoginResult::Success(s) => assert_eq!(s, salt),
+            _ => panic!("login against the persisted state failed"),
+        }
+        assert!(reopened.node_name_taken(email, &pass_sha256, node_name).await.unwrap());
+
+        let _ = fs::remove_dir_all(&root);
+    }
 }
@@ -1,15 +1,17 @@
-use crate::consts::SERVER_ENDPOINT;
+use crate::{config_utils, consts::DEFAULT_NGROK_WEB_ADDR, control_plane};
 use anyhow::{anyhow, Result};
-use bincode;
-use reqwest;
 use std::{
     fs,
-    process::{self, Command, Stdio},
+    process::{self, Child, Command, Stdio},
+    sync::Mutex,
     time::Duration,
 };
 use tokio::process::Command as TkCommand;
 use tynkerbase_universal::{crypt_utils::aes_utils, netwk_utils::Node};
 
+// The ngrok process started by `spawn_ngrok`, kept so it can be stopped on shutdown
+static NGROK_CHILD: Mutex<Option<Child>> = Mutex::new(None);
+
 pub async fn store_token<T: AsRef<str>>(
     email: T,
     pass_sha256: T,
@@ -29,22 +31,7 @@ pub async fn store_token<T: AsRef<str>>(
     let aes_ng =
         bincode::serialize(&aes_ng).map_err(|e| anyhow!("Failed to serialize token -> {}", e))?;
 
-    // Send request
-    let endpoint =
-        format!("{SERVER_ENDPOINT}/ngrok/save-ng-auth?email={email}&pass_sha256={pass_sha256}");
-
-    let res = reqwest::Client::new()
-        .post(&endpoint)
-        .body(aes_ng)
-        .send()
-        .await
-        .map_err(|e| anyhow!("Error sending req -> {}", e))?;
-
-    if !res.status().is_success() {
-        return Err(anyhow!("{:?}", res.status()));
-    }
-
-    Ok(())
+    control_plane::get().save_ngrok_token(email, pass_sha256, aes_ng).await
 }
 
 pub async fn get_token(
@@ -56,34 +43,11 @@ pub async fn get_token(
     let pass_sha256 = pass_sha256.as_ref();
     let tyb_apikey = tyb_apikey.as_ref();
 
-    // Send request
-    let endpoint =
-        format!("{SERVER_ENDPOINT}/ngrok/get-ng-auth?email={email}&pass_sha256={pass_sha256}");
-
-    let res = reqwest::Client::new()
-        .get(&endpoint)
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await;
-
-    let res = match res {
-        Ok(r) => r,
-        Err(e) => {
-            #[cfg(debug_assertions)]
-            {
-                println!("Error getting ngrok token -> {}", e);
-            }
-            return None;
-        }
-    };
-
-    let body = match res.bytes().await {
-        Ok(r) => r.to_vec(),
+    let body = match control_plane::get().get_ngrok_token(email, pass_sha256).await {
+        Ok(Some(b)) => b,
+        Ok(None) => return None,
         Err(e) => {
-            #[cfg(debug_assertions)]
-            {
-                println!("Error getting bytes from response -> {}", e);
-            }
+            log::debug!("Error getting ngrok token -> {}", e);
             return None;
         }
     };
@@ -111,7 +75,7 @@ stable.
 
 pub async fn attach_token(ng_token: impl AsRef<str>) -> Result<()> {
     let ng_token = ng_token.as_ref();
-    let child = TkCommand::new("ngrok")
+    let child = TkCommand::new(&config_utils::get().tunnel.ngrok_path)
         .args(["config", "add-authtoken", ng_token])
         .output()
         .await
@@ -126,7 +90,7 @@ pub async fn attach_token(ng_token: impl AsRef<str>) -> Result<()> {
 
 #[allow(dead_code)]
 pub async fn token_is_installed() -> Result<bool> {
-    let child = TkCommand::new("ngrok")
+    let child = TkCommand::new(&config_utils::get().tunnel.ngrok_path)
         .args(["config", "check"])
         .output()
         .await
@@ -137,7 +101,7 @@ pub async fn token_is_installed() -> Result<bool> {
         #[cfg(debug_assertions)]
         {
             println!("Running `ngrok config check` for debug mode.");
-            let mut child = TkCommand::new("ngrok")
+            let mut child = TkCommand::new(&config_utils::get().tunnel.ngrok_path)
                 .args(["config", "check"])
                 .spawn()
                 .unwrap();
@@ -169,19 +133,31 @@ pub async fn token_is_installed() -> Result<bool> {
     Ok(config_file.contains("authtoken:"))
 }
 
-// Uses ngrok to make service public and inserts the public address into mongo
+// Uses ngrok to make service public and registers the public address with the control plane
 pub async fn make_public<T: AsRef<str>>(
     email: T,
     pass_sha256: T,
     node_id: T,
     name: T,
 ) -> Result<String> {
+    let public_addr = spawn_ngrok(config_utils::get().tunnel.startup_timeout_secs).await?;
+    register_addr(email, pass_sha256, node_id, name, &public_addr).await?;
+    Ok(public_addr)
+}
+
+// Registers the public address of this node with the control plane
+pub async fn register_addr(
+    email: impl AsRef<str>,
+    pass_sha256: impl AsRef<str>,
+    node_id: impl AsRef<str>,
+    name: impl AsRef<str>,
+    public_addr: impl AsRef<str>,
+) -> Result<()> {
     let email = email.as_ref();
     let pass_sha256 = pass_sha256.as_ref();
     let node_id = node_id.as_ref();
     let name = name.as_ref();
-
-    let public_addr = spawn_ngrok(10.).await?;
+    let public_addr = public_addr.as_ref();
 
     let node = Node {
         email: email.to_string(),
@@ -189,33 +165,83 @@ pub async fn make_public<T: AsRef<str>>(
         name: name.to_string(),
         addr: public_addr.to_string(),
     };
-    let bin = bincode::serialize(&node).unwrap();
+    control_plane::get().add_node_addr(email, pass_sha256, &node).await
+}
 
-    let endpoint =
-        format!("{SERVER_ENDPOINT}/ngrok/add-addr?email={email}&pass_sha256={pass_sha256}");
+// Removes this node's public address from the control plane so clients stop trying to reach it
+pub async fn deregister_addr(
+    email: impl AsRef<str>,
+    pass_sha256: impl AsRef<str>,
+    node_id: impl AsRef<str>,
+    name: impl AsRef<str>,
+) -> Result<()> {
+    let email = email.as_ref();
+    let pass_sha256 = pass_sha256.as_ref();
 
-    #[allow(unused)]
-    let res = reqwest::Client::new()
-        .post(&endpoint)
-        .body(bin)
-        .send()
-        .await
-        .map_err(|e| anyhow!("Error sending req -> {}", e))?;
-
-    #[cfg(debug_assertions)]
-    {
-        println!("RES -> {:#?}\n\n", &res);
-        println!("RES TEXT -> {:?}\n\n", res.text().await);
-        // if !res.status().is_success() {
-        //     println!("Error in API call saving public address to mongo:\n\n{:#?}", res);
-        //     process::exit(0);
-        // }
+    let node = Node {
+        email: email.to_string(),
+        node_id: node_id.as_ref().to_string(),
+        name: name.as_ref().to_string(),
+        addr: String::new(),
+    };
+    control_plane::get().remove_node_addr(email, pass_sha256, &node).await
+}
+
+/// Whether the ngrok process started by `spawn_ngrok` is still running.
+pub fn tunnel_running() -> bool {
+    match NGROK_CHILD.lock() {
+        Ok(mut lock) => lock.as_mut().is_some_and(|c| matches!(c.try_wait(), Ok(None))),
+        _ => false,
     }
+}
 
-    Ok(public_addr)
+/// Stops the tunnel started by `spawn_ngrok`, if there is one.
+pub fn stop_ngrok() -> Result<()> {
+    let mut lock = NGROK_CHILD
+        .lock()
+        .map_err(|e| anyhow!("ngrok process lock poisoned -> {}", e))?;
+    if let Some(mut child) = lock.take() {
+        child.kill().map_err(|e| anyhow!("Failed to stop ngrok -> {}", e))?;
+        let _ = child.wait();
+    }
+    Ok(())
+}
+
+// ngrok's own config file, where `ngrok config add-authtoken` puts the token
+fn default_config_path(ngrok_path: &str) -> Option<String> {
+    let output = Command::new(ngrok_path).args(["config", "check"]).output().ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    let path = output.split_once('/')?.1.trim();
+    Some(format!("/{path}"))
 }
 
-async fn spawn_ngrok(timeout: f64) -> Result<String> {
+// ngrok serves its local API on 4040 (or the next free port) unless told otherwise, so
+// instances other than the default one get a config of their own layered over ngrok's
+fn uses_instance_config() -> bool {
+    let config = config_utils::get();
+    config.instance.is_some() || config.tunnel.web_addr != DEFAULT_NGROK_WEB_ADDR
+}
+
+// The instance's `tunnel.authtoken` is handed to ngrok through `NGROK_AUTHTOKEN` rather than
+// this file, so it never sits on disk where other users of the host could read it
+fn instance_config_args() -> Result<Vec<String>> {
+    let config = config_utils::get();
+    if !uses_instance_config() {
+        return Ok(vec![]);
+    }
+
+    let yml = format!("version: \"2\"\nweb_addr: {}\n", config.tunnel.web_addr);
+    let path = format!("{}/ngrok.yml", config_utils::root_dir());
+    fs::write(&path, yml).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))?;
+
+    let configs = match default_config_path(&config.tunnel.ngrok_path) {
+        Some(default) => format!("{default},{path}"),
+        None => path,
+    };
+    Ok(vec!["--config".to_string(), configs])
+}
+
+pub async fn spawn_ngrok(timeout: f64) -> Result<String> {
     /*
     Unfortunately, the ngrok rust driver doesn't seem to work.
     Additionally, the ngrok CLI tool displays output in a terminal UI, not in stdio, so
@@ -225,16 +251,27 @@ async fn spawn_ngrok(timeout: f64) -> Result<String> {
     public url)
     */
 
-    let _ = Command::new("ngrok")
-        .args(["http", "https://localhost:7462"])
-        .stdout(Stdio::null())
+    let config = config_utils::get();
+    let local_addr = format!("https://localhost:{}", config.server.port);
+    let mut command = Command::new(&config.tunnel.ngrok_path);
+    command
+        .args(["http", &local_addr])
+        .args(instance_config_args()?)
+        .stdout(Stdio::null());
+    if let Some(token) = config.tunnel.authtoken.as_ref().filter(|_| uses_instance_config()) {
+        command.env("NGROK_AUTHTOKEN", token);
+    }
+    let child = command
         .spawn()
-        .unwrap();
+        .map_err(|e| anyhow!("Failed to start ngrok -> {}", e))?;
+    if let Ok(mut lock) = NGROK_CHILD.lock() {
+        *lock = Some(child);
+    }
 
     for _ in 0..10 {
         tokio::time::sleep(Duration::from_secs_f64(timeout / 10.)).await;
 
-        let res = reqwest::get("http://localhost:4040/api/tunnels/").await;
+        let res = reqwest::get(format!("http://{}/api/tunnels/", config.tunnel.web_addr)).await;
         let res = match res {
             Ok(r) => r,
             _ => continue,
@@ -246,7 +283,8 @@ async fn spawn_ngrok(timeout: f64) -> Result<String> {
             _ => continue,
         };
 
-        if !url.contains("\"public_url\":\"") {
+        // Make sure it's this instance's tunnel and not another agent's on the same host
+        if !url.contains("\"public_url\":\"") || !url.contains(&local_addr) {
             continue;
         }
 
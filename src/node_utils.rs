@@ -0,0 +1,152 @@
+use crate::{auth_utils, config_utils};
+use anyhow::{anyhow, Result};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+// Bump when the identity file changes in a way older agents can't read
+const IDENTITY_VERSION: u32 = 1;
+const NODE_ID_LEN: usize = 32;
+const MAX_NODE_NAME_LEN: usize = 64;
+
+/// Who this node is to the control server. Stored as JSON so fields can be added
+/// (with `#[serde(default)]`) without breaking files written by older agents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeIdentity {
+    pub version: u32,
+    pub node_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub renamed_at: Option<u64>,
+}
+
+impl NodeIdentity {
+    pub fn new(name: String) -> Self {
+        let node_id = (0..NODE_ID_LEN)
+            .map(|_| thread_rng().gen_range(b'a'..=b'z') as char)
+            .collect();
+        NodeIdentity {
+            version: IDENTITY_VERSION,
+            node_id,
+            name,
+            created_at: auth_utils::now_secs(),
+            renamed_at: None,
+        }
+    }
+}
+
+fn get_identity_path() -> String {
+    format!("{}/data/node-identity.json", config_utils::root_dir())
+}
+
+// Written by agents before the identity file was versioned, a bincode `(node_id, name)`
+fn get_legacy_path() -> String {
+    format!("{}/data/node-info.bin", config_utils::root_dir())
+}
+
+/// Whether this node has an identity (in either format) on disk.
+pub fn identity_exists() -> bool {
+    Path::new(&get_identity_path()).exists() || Path::new(&get_legacy_path()).exists()
+}
+
+fn read_current() -> Result<Option<NodeIdentity>> {
+    let path = get_identity_path();
+    let text = match fs::read_to_string(&path) {
+        Ok(t) => t,
+        _ => return Ok(None),
+    };
+    let identity: NodeIdentity = serde_json::from_str(&text)
+        .map_err(|e| anyhow!("Node identity `{path}` is corrupted, fix or remove it -> {e}"))?;
+    if identity.version > IDENTITY_VERSION {
+        return Err(anyhow!(
+            "Node identity `{}` was written by a newer agent (version {}), please update",
+            path,
+            identity.version
+        ));
+    }
+    Ok(Some(identity))
+}
+
+fn read_legacy() -> Result<Option<NodeIdentity>> {
+    let legacy_path = get_legacy_path();
+    let bin = match fs::read(&legacy_path) {
+        Ok(b) => b,
+        _ => return Ok(None),
+    };
+    let (node_id, name): (String, String) = bincode::deserialize(&bin)
+        .map_err(|e| anyhow!("Node identity `{legacy_path}` is corrupted, fix or remove it -> {e}"))?;
+    Ok(Some(NodeIdentity {
+        version: IDENTITY_VERSION,
+        node_id,
+        name,
+        created_at: 0,
+        renamed_at: None,
+    }))
+}
+
+/// Reads the node's identity in either format without migrating it, for commands that only
+/// report on the agent. Returns `None` if the node hasn't been registered yet.
+pub fn read_identity() -> Result<Option<NodeIdentity>> {
+    match read_current()? {
+        Some(identity) => Ok(Some(identity)),
+        None => read_legacy(),
+    }
+}
+
+/// Loads the node's identity, migrating the legacy format if that's all there is.
+/// Returns `None` if the node hasn't been registered yet.
+pub fn load_identity() -> Result<Option<NodeIdentity>> {
+    if let Some(identity) = read_current()? {
+        return Ok(Some(identity));
+    }
+    let identity = match read_legacy()? {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    save_identity(&identity)?;
+    let _ = fs::remove_file(get_legacy_path());
+    Ok(Some(identity))
+}
+
+pub fn save_identity(identity: &NodeIdentity) -> Result<()> {
+    let dir = format!("{}/data", config_utils::root_dir());
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+
+    let json = serde_json::to_string_pretty(identity)
+        .map_err(|e| anyhow!("Failed to serialize node identity -> {e}"))?;
+    // Write then rename so a crash can't leave a half written identity behind
+    let path = get_identity_path();
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, json).map_err(|e| anyhow!("Failed to write `{tmp_path}` -> {e}"))?;
+    fs::rename(&tmp_path, &path).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))
+}
+
+/// Deletes the node's identity so it is registered as a new node on the next login.
+pub fn delete_identity() -> Result<()> {
+    for path in [get_identity_path(), get_legacy_path()] {
+        if Path::new(&path).exists() {
+            fs::remove_file(&path).map_err(|e| anyhow!("Failed to remove `{path}` -> {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_node_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(anyhow!("Node name must not be empty"));
+    }
+    if name.trim() != name {
+        return Err(anyhow!("Node name must not start or end with whitespace"));
+    }
+    if name.chars().count() > MAX_NODE_NAME_LEN {
+        return Err(anyhow!("Node name must be at most {} characters", MAX_NODE_NAME_LEN));
+    }
+    if name.chars().any(|c| c.is_control() || c == '&' || c == '#' || c == '?') {
+        return Err(anyhow!("Node name `{}` contains characters that aren't allowed", name));
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+/*
+What the agent knows about each project besides its files, kept in
+`{root_dir}/data/projects/{project}.json`. A directory in the projects dir only counts as a
+project if it has this file, so stray folders aren't listed. Projects created before the
+metadata existed are adopted the first time the agent starts or lists its projects.
+*/
+
+use crate::{auth_utils, config_utils, proj_utils::ProjName};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use tynkerbase_universal::netwk_utils::ProjConfig;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProjMeta {
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub last_upload: Option<u64>,
+    #[serde(default)]
+    pub last_build: Option<u64>,
+    #[serde(default)]
+    pub image_id: Option<String>,
+    #[serde(default)]
+    pub last_spawn: Option<u64>,
+    // The config the project's container was last spawned with
+    #[serde(default)]
+    pub last_config: Option<ProjConfig>,
+}
+
+/// A project as returned by `list-projects`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjInfo {
+    pub name: String,
+    pub meta: ProjMeta,
+    pub size_bytes: u64,
+    // The docker state of the project's container (`running`, `exited`, ...), `None` if there is
+    // none and `unknown` if docker couldn't be asked
+    pub container_state: Option<String>,
+}
+
+fn get_meta_dir() -> String {
+    format!("{}/data/projects", config_utils::root_dir())
+}
+
+fn get_meta_path(name: &str) -> String {
+    format!("{}/{}.json", get_meta_dir(), name)
+}
+
+pub fn exists(name: &str) -> bool {
+    Path::new(&get_meta_path(name)).exists()
+}
+
+pub fn load(name: &ProjName) -> Result<ProjMeta> {
+    let path = get_meta_path(name.as_str());
+    let text = match fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(_) => return Err(anyhow!("Project `{}` does not exist.", name)),
+    };
+    serde_json::from_str(&text).map_err(|e| anyhow!("Project metadata `{path}` is corrupted -> {e}"))
+}
+
+fn save(name: &str, meta: &ProjMeta) -> Result<()> {
+    let dir = get_meta_dir();
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+    let json = serde_json::to_string_pretty(meta)
+        .map_err(|e| anyhow!("Failed to serialize project metadata -> {e}"))?;
+    // Write then rename so a crash can't leave half written metadata behind
+    let path = get_meta_path(name);
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, json).map_err(|e| anyhow!("Failed to write `{tmp_path}` -> {e}"))?;
+    fs::rename(&tmp_path, &path).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))
+}
+
+/// Starts the metadata of a newly created project.
+pub fn create(name: &ProjName) -> Result<()> {
+    let meta = ProjMeta {
+        created_at: auth_utils::now_secs(),
+        ..Default::default()
+    };
+    save(name.as_str(), &meta)
+}
+
+/// Loads, changes and saves the project's metadata. Metadata only adds information, so
+/// callers log a failure rather than failing the request.
+pub fn update<F: FnOnce(&mut ProjMeta)>(name: &ProjName, f: F) -> Result<()> {
+    let mut meta = load(name)?;
+    f(&mut meta);
+    save(name.as_str(), &meta)
+}
+
+pub fn delete(name: &ProjName) -> Result<()> {
+    let path = get_meta_path(name.as_str());
+    if Path::new(&path).exists() {
+        fs::remove_file(&path).map_err(|e| anyhow!("Failed to remove `{path}` -> {e}"))?;
+    }
+    Ok(())
+}
+
+/// Whether the projects that existed before the metadata did have been adopted.
+pub fn adopted() -> bool {
+    Path::new(&get_meta_dir()).exists()
+}
+
+/// Writes metadata for every project directory if the agent has never kept any, taking the
+/// directory's creation time as the project's.
+pub fn adopt_existing_projects() -> Result<()> {
+    if adopted() {
+        return Ok(());
+    }
+    let entries = match fs::read_dir(config_utils::projects_dir()) {
+        Ok(e) => e,
+        _ => return Ok(()),
+    };
+    for entry in entries.flatten() {
+        let name = match entry.file_name().into_string() {
+            Ok(n) if ProjName::parse(&n).is_ok() => n,
+            _ => continue,
+        };
+        let metadata = match entry.metadata() {
+            Ok(m) if m.is_dir() => m,
+            _ => continue,
+        };
+        let created_at = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        save(&name, &ProjMeta { created_at, ..Default::default() })?;
+    }
+    // Also marks the adoption as done when there was nothing to adopt
+    fs::create_dir_all(get_meta_dir()).map_err(|e| anyhow!("Failed to create `{}` -> {e}", get_meta_dir()))
+}
+
+/// Total size of the files under `path`, symlinks aren't followed.
+pub fn size_on_disk(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(e) => e,
+        _ => return 0,
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => size_on_disk(&entry.path()),
+            Ok(t) if t.is_file() => entry.metadata().map_or(0, |m| m.len()),
+            _ => 0,
+        })
+        .sum()
+}
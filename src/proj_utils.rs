@@ -1,16 +1,113 @@
-use tynkerbase_universal::{constants::LINUX_TYNKERBASE_PATH, file_utils::FileCollection};
+use crate::{auth_utils, config_utils, proj_meta_utils, snapshot_utils};
+use tynkerbase_universal::file_utils::FileCollection;
 
 use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     env::consts::OS,
-    fs,
-    path::{Path, PathBuf},
+    ffi::CString,
+    fmt,
+    fs::{self, File},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
 };
 
-pub fn create_proj(name: &str) -> Result<String> {
+const MAX_PROJ_NAME_LEN: usize = 64;
+
+// Projects with an upload, build, delete or snapshot prune running, only one of them can run
+// on a project at a time
+static UPLOADS_IN_PROGRESS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Maps each file's path (relative to the project directory, `/` separated) to the sha256 of
+/// its contents.
+pub type Manifest = BTreeMap<String, String>;
+
+/// An incremental upload: the files that were added or changed since the manifest the client
+/// diffed against, and the paths of the files that were removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjDiff {
+    pub files: FileCollection,
+    pub deleted: Vec<String>,
+}
+
+/// A project name that is safe to use as a directory under the projects directory
+/// and as part of docker image and container names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjName(String);
+
+impl ProjName {
+    /// Project names are lowercase letters and digits, optionally split into groups by a
+    /// single `-`, `_` or `.` (e.g. `my-app`, `api.v2`).
+    pub fn parse(name: &str) -> Result<Self> {
+        if name.is_empty() {
+            return Err(anyhow!("Project name cannot be empty"));
+        }
+        if name.len() > MAX_PROJ_NAME_LEN {
+            return Err(anyhow!(
+                "Project name `{}` is longer than {} characters",
+                name,
+                MAX_PROJ_NAME_LEN
+            ));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.')))
+        {
+            return Err(anyhow!(
+                "Project name `{}` contains `{}`, only lowercase letters, digits, `-`, `_` and `.` are allowed",
+                name,
+                c
+            ));
+        }
+        let is_sep = |c: char| matches!(c, '-' | '_' | '.');
+        if name.starts_with(is_sep) || name.ends_with(is_sep) {
+            return Err(anyhow!(
+                "Project name `{}` must start and end with a letter or digit",
+                name
+            ));
+        }
+        if name.as_bytes().windows(2).any(|w| is_sep(w[0] as char) && is_sep(w[1] as char)) {
+            return Err(anyhow!(
+                "Project name `{}` cannot contain consecutive `-`, `_` or `.` characters",
+                name
+            ));
+        }
+        Ok(ProjName(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn image_name(&self) -> String {
+        format!("{}{}", self.0, config_utils::image_suffix())
+    }
+
+    pub fn container_name(&self) -> String {
+        format!("{}{}", self.0, config_utils::container_suffix())
+    }
+
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(config_utils::projects_dir());
+        path.push(&self.0);
+        path
+    }
+}
+
+impl fmt::Display for ProjName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+pub fn create_proj(name: &ProjName) -> Result<String> {
     if OS == "linux" {
         // Ensure project directory exists first
-        let mut root_path = PathBuf::from(LINUX_TYNKERBASE_PATH);
+        let mut root_path = PathBuf::from(config_utils::projects_dir());
         if !root_path.exists() {
             fs::create_dir_all(&root_path).map_err(|e| {
                 anyhow!(
@@ -21,43 +118,242 @@ pub fn create_proj(name: &str) -> Result<String> {
             })?;
         }
 
-        root_path.push(name);
+        root_path.push(name.as_str());
         if root_path.exists() {
             return Err(anyhow!("Project `{}` already exists", name));
         }
-        if let Err(e) = fs::create_dir_all(root_path) {
+        if let Err(e) = fs::create_dir_all(&root_path) {
             return Err(anyhow!("Error creating dir: `{}`", e));
         }
-        return Ok(format!("Created `{LINUX_TYNKERBASE_PATH}/{name}`"));
+        if let Err(e) = proj_meta_utils::create(name) {
+            let _ = fs::remove_dir_all(&root_path);
+            return Err(e);
+        }
+        return Ok(format!("Created `{}/{name}`", config_utils::projects_dir()));
     }
     Err(anyhow!("OS `{}` is unsupported", OS))
 }
 
-pub fn add_files_to_proj(name: &str, files: FileCollection) -> Result<()> {
-    if OS == "linux" {
-        let proj_path = format!("{LINUX_TYNKERBASE_PATH}/{name}");
-        if !Path::new(&proj_path).exists() {
-            return Err(anyhow!("Project `{}` does not exist.", { name }));
+/// Marks a project as having an upload (or another change to its files) in progress until
+/// dropped, see `lock_proj`.
+pub struct UploadGuard(String);
+
+/// Fails if an upload, build, delete or snapshot prune of the project is already running.
+pub fn lock_proj(name: &ProjName) -> Result<UploadGuard> {
+    let mut lock = UPLOADS_IN_PROGRESS
+        .lock()
+        .map_err(|e| anyhow!("Upload lock poisoned -> {e}"))?;
+    if !lock.insert(name.to_string()) {
+        return Err(anyhow!("An upload or other change to project `{}` is already in progress", name));
+    }
+    Ok(UploadGuard(name.to_string()))
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        if let Ok(mut lock) = UPLOADS_IN_PROGRESS.lock() {
+            lock.remove(&self.0);
+        }
+    }
+}
+
+// Staged uploads and the contents they replace are kept next to the project. The leading `.`
+// means they're never taken for a project. `.previous` is only used where the two can't be
+// exchanged atomically.
+fn get_staging_path(name: &ProjName) -> PathBuf {
+    PathBuf::from(config_utils::projects_dir()).join(format!(".{name}.staging"))
+}
+
+fn get_previous_path(name: &ProjName) -> PathBuf {
+    PathBuf::from(config_utils::projects_dir()).join(format!(".{name}.previous"))
+}
+
+// Puts back a project the agent stopped in the middle of swapping, and removes what's left of
+// earlier uploads
+fn recover_upload(name: &ProjName) -> Result<()> {
+    let (proj_path, staging_path, previous_path) = (name.path(), get_staging_path(name), get_previous_path(name));
+    if !proj_path.exists() && previous_path.exists() {
+        fs::rename(&previous_path, &proj_path)
+            .map_err(|e| anyhow!("Failed to restore `{}` -> {e}", proj_path.display()))?;
+    }
+    for path in [staging_path, previous_path] {
+        if path.exists() {
+            fs::remove_dir_all(&path).map_err(|e| anyhow!("Failed to remove `{}` -> {e}", path.display()))?;
         }
+    }
+    Ok(())
+}
 
-        if let Err(e) = files.save(&proj_path) {
-            return Err(anyhow!("{}", e));
+pub fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).map_err(|e| anyhow!("Failed to create `{}` -> {e}", to.display()))?;
+    let entries = fs::read_dir(from).map_err(|e| anyhow!("Failed to read `{}` -> {e}", from.display()))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| anyhow!("Failed to read `{}` -> {e}", from.display()))?;
+        let (src, dst) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry
+            .file_type()
+            .map_err(|e| anyhow!("Failed to stat `{}` -> {e}", src.display()))?;
+        if file_type.is_dir() {
+            copy_dir(&src, &dst)?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&src).map_err(|e| anyhow!("Failed to read `{}` -> {e}", src.display()))?;
+            std::os::unix::fs::symlink(target, &dst)
+                .map_err(|e| anyhow!("Failed to create `{}` -> {e}", dst.display()))?;
+        } else {
+            fs::copy(&src, &dst).map_err(|e| anyhow!("Failed to copy `{}` -> {e}", src.display()))?;
         }
-        return Ok(());
     }
-    Err(anyhow!("OS `{}` is unsupported", OS))
+    Ok(())
+}
+
+// Atomically swaps the two directories with `renameat2(RENAME_EXCHANGE)`, so there is no moment
+// where neither is in place
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let (a, b) = (CString::new(a.as_os_str().as_bytes())?, CString::new(b.as_os_str().as_bytes())?);
+    // SAFETY: both paths are valid, nul terminated strings that outlive the call
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Replaces the project with the staged directory, atomically where the kernel and filesystem
+// support it. Otherwise the previous contents are moved aside, and moved back if the staged ones
+// can't be put in place. The previous contents are only removed once the staged ones are in place.
+fn swap_into_place(name: &ProjName) -> Result<()> {
+    let (proj_path, staging_path, previous_path) = (name.path(), get_staging_path(name), get_previous_path(name));
+
+    let is_empty = fs::read_dir(&staging_path)
+        .map_err(|e| anyhow!("Failed to read `{}` -> {e}", staging_path.display()))?
+        .next()
+        .is_none();
+    if is_empty {
+        return Err(anyhow!("Upload contains no files, project `{}` was left unchanged", name));
+    }
+
+    match exchange(&staging_path, &proj_path) {
+        Ok(_) => {
+            // The staging directory now holds the previous contents
+            if let Err(e) = fs::remove_dir_all(&staging_path) {
+                log::warn!("Failed to remove `{}` -> {}", staging_path.display(), e);
+            }
+            return Ok(());
+        }
+        // Kernels before 3.15 and some filesystems can't exchange
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EINVAL)) => {}
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+        Err(e) => return Err(anyhow!("Failed to move the upload into place -> {e}")),
+    }
+
+    fs::rename(&proj_path, &previous_path)
+        .map_err(|e| anyhow!("Failed to move `{}` aside -> {e}", proj_path.display()))?;
+    if let Err(e) = fs::rename(&staging_path, &proj_path) {
+        if let Err(restore_err) = fs::rename(&previous_path, &proj_path) {
+            return Err(anyhow!(
+                "Failed to move the upload into place -> {e}. Restoring `{}` failed too, the previous files are in `{}` -> {restore_err}",
+                proj_path.display(),
+                previous_path.display()
+            ));
+        }
+        return Err(anyhow!("Failed to move the upload into place -> {e}"));
+    }
+
+    if let Err(e) = fs::remove_dir_all(&previous_path) {
+        log::warn!("Failed to remove `{}` -> {}", previous_path.display(), e);
+    }
+    Ok(())
+}
+
+/// Builds the project's new contents in a staging directory with `fill`, then swaps them in.
+/// Whatever goes wrong, the project is left as it was. With `snapshot`, the new contents are
+/// also kept as a version (see `snapshot_utils`).
+pub fn stage_upload<F>(name: &ProjName, snapshot: bool, fill: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    if OS != "linux" {
+        return Err(anyhow!("OS `{}` is unsupported", OS));
+    }
+    let _guard = lock_proj(name)?;
+    recover_upload(name)?;
+    if !name.path().exists() {
+        return Err(anyhow!("Project `{}` does not exist.", name));
+    }
+
+    let staging_path = get_staging_path(name);
+    let res = fill(&staging_path).and_then(|_| swap_into_place(name));
+    if res.is_err() && staging_path.exists() {
+        let _ = fs::remove_dir_all(&staging_path);
+    }
+    res?;
+
+    let now = auth_utils::now_secs();
+    if let Err(e) = proj_meta_utils::update(name, |m| m.last_upload = Some(now)) {
+        log::warn!("Failed to update the metadata of project `{}` -> {}", name, e);
+    }
+    // The upload itself succeeded, a missing snapshot only costs the option to roll back to it
+    if snapshot {
+        if let Err(e) = snapshot_utils::take(name) {
+            log::warn!("Failed to snapshot project `{}` -> {}", name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces every file in the project with `files`.
+pub fn add_files_to_proj(name: &ProjName, files: FileCollection) -> Result<()> {
+    stage_upload(name, true, |staging_path| {
+        fs::create_dir_all(staging_path)
+            .map_err(|e| anyhow!("Failed to create `{}` -> {e}", staging_path.display()))?;
+        files
+            .save(&staging_path.to_string_lossy())
+            .map_err(|e| anyhow!("Failed to save files -> {e}"))
+    })
 }
 
 pub fn get_proj_names() -> Vec<String> {
+    if let Err(e) = proj_meta_utils::adopt_existing_projects() {
+        log::warn!("Failed to write metadata for existing projects -> {}", e);
+    }
+    read_proj_names()
+}
+
+/// The projects `get_proj_names` returns, without writing metadata for the ones that don't have
+/// any yet. For commands that only report on the agent.
+pub fn read_proj_names() -> Vec<String> {
+    // Until existing projects are adopted, every directory that would be adopted counts
+    let adopted = proj_meta_utils::adopted();
+
     // traverses the tynkerbase-projects directory to get all the names of all the folders
-    // (which should each contain a project)
-    let projects = fs::read_dir(format!("{LINUX_TYNKERBASE_PATH}"));
+    // that have metadata (and so were created as projects)
+    let projects = fs::read_dir(config_utils::projects_dir());
     match projects {
         Ok(projects) => {
             let mut res = vec![];
-            for path in projects {
-                if let Ok(path) = path {
-                    if let Ok(path) = path.file_name().into_string() {
+            for path in projects.flatten() {
+                let is_dir = path.file_type().is_ok_and(|t| t.is_dir());
+                if let Ok(path) = path.file_name().into_string() {
+                    let is_proj = match adopted {
+                        true => proj_meta_utils::exists(&path),
+                        false => is_dir,
+                    };
+                    if ProjName::parse(&path).is_ok() && is_proj {
                         res.push(path);
                     }
                 }
@@ -68,38 +364,172 @@ pub fn get_proj_names() -> Vec<String> {
     }
 }
 
-pub fn delete_proj(name: &str) -> Result<()> {
+pub fn delete_proj(name: &ProjName) -> Result<()> {
     if OS == "linux" {
-        let path = format!("{LINUX_TYNKERBASE_PATH}/{name}");
+        let _guard = lock_proj(name)?;
+        let path = format!("{}/{name}", config_utils::projects_dir());
         if !Path::new(&path).exists() {
             return Err(anyhow!("Project does not exist"));
         }
         if let Err(e) = fs::remove_dir_all(path) {
             return Err(anyhow!("{}", e));
         }
+        snapshot_utils::delete_all(name)?;
+        proj_meta_utils::delete(name)?;
     }
     Ok(())
 }
 
-pub fn clear_proj(name: &str) -> Result<()> {
-    let res = delete_proj(name);
-    if res.is_err() {
-        return res;
-    }
-    let res = create_proj(name);
-    if let Err(e) = res {
-        return Err(anyhow!("{}", e));
+pub fn load_proj_files(name: &ProjName, ignore: Option<&Vec<String>>) -> Result<FileCollection> {
+    let path_str = format!("{}/{}", config_utils::projects_dir(), name);
+    let empty_vec: Vec<String> = vec![];
+    let ignore = ignore.unwrap_or(&empty_vec);
+
+    FileCollection::load(&path_str, ignore)
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).map_err(|e| anyhow!("Failed to open `{}` -> {e}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| anyhow!("Failed to read `{}` -> {e}", path.display()))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn add_to_manifest(root: &Path, dir: &Path, manifest: &mut Manifest) -> Result<()> {
+    let entries = fs::read_dir(dir).map_err(|e| anyhow!("Failed to read `{}` -> {e}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| anyhow!("Failed to read `{}` -> {e}", dir.display()))?;
+        let path = entry.path();
+        // Symlinks aren't followed, they could point outside of the project
+        let file_type = entry
+            .file_type()
+            .map_err(|e| anyhow!("Failed to stat `{}` -> {e}", path.display()))?;
+        if file_type.is_dir() {
+            add_to_manifest(root, &path, manifest)?;
+        } else if file_type.is_file() {
+            let rel_path = path
+                .strip_prefix(root)
+                .map_err(|e| anyhow!("`{}` is outside of the project -> {e}", path.display()))?;
+            let rel_path: Vec<_> = rel_path.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+            manifest.insert(rel_path.join("/"), hash_file(&path)?);
+        }
     }
     Ok(())
 }
 
-pub fn load_proj_files(name: &str, ignore: Option<&Vec<String>>) -> Result<FileCollection> {
-    let path_str = format!("{}/{}", LINUX_TYNKERBASE_PATH, name);
-    let empty_vec: Vec<String> = vec![];
-    let ignore = ignore.unwrap_or(&empty_vec);
+/// Hashes every file under `path`.
+pub fn dir_manifest(path: &Path) -> Result<Manifest> {
+    let mut manifest = Manifest::new();
+    add_to_manifest(path, path, &mut manifest)?;
+    Ok(manifest)
+}
 
-    match FileCollection::load(&path_str, &ignore) {
-        Ok(fc) => Ok(fc),
-        Err(e) => Err(e),
+/// Hashes every file in the project, so clients can work out which files they need to send.
+pub fn manifest(name: &ProjName) -> Result<Manifest> {
+    let proj_path = name.path();
+    if !proj_path.exists() {
+        return Err(anyhow!("Project `{}` does not exist.", name));
+    }
+    dir_manifest(&proj_path)
+}
+
+// Resolves a path from a diff, which must stay inside of the project directory
+fn resolve_proj_path(proj_path: &Path, rel_path: &str) -> Result<PathBuf> {
+    let rel = Path::new(rel_path);
+    if rel_path.is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(anyhow!("`{}` is not a relative path inside of the project", rel_path));
+    }
+    Ok(proj_path.join(rel))
+}
+
+/// Applies an incremental upload to a copy of the project: removes the deleted files (and the
+/// directories they leave empty), then saves the added and changed ones over the existing files.
+/// Every deleted path is checked before anything is touched. Returns how many files were removed.
+pub fn apply_diff(name: &ProjName, diff: ProjDiff) -> Result<usize> {
+    let proj_path = name.path();
+    let mut removed = 0;
+    stage_upload(name, true, |staging_path| {
+        let to_delete = diff
+            .deleted
+            .iter()
+            .map(|p| resolve_proj_path(staging_path, p))
+            .collect::<Result<Vec<_>>>()?;
+        copy_dir(&proj_path, staging_path)?;
+
+        for path in to_delete {
+            // Already gone, e.g. a retried diff
+            if !path.is_file() {
+                continue;
+            }
+            fs::remove_file(&path).map_err(|e| anyhow!("Failed to remove `{}` -> {e}", path.display()))?;
+            removed += 1;
+
+            let mut dir = path.parent();
+            while let Some(d) = dir {
+                if d == staging_path || fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+
+        diff.files
+            .save(&staging_path.to_string_lossy())
+            .map_err(|e| anyhow!("Failed to save files -> {e}"))
+    })?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_names() {
+        for name in ["app", "my-app", "api.v2", "a_b-c.d", "x1", &"a".repeat(MAX_PROJ_NAME_LEN)] {
+            assert_eq!(ProjName::parse(name).unwrap().as_str(), name);
+        }
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        for name in ["..", ".", "../app", "app/..", "a/b", "/app", "/etc/passwd", "app\\x"] {
+            assert!(ProjName::parse(name).is_err(), "`{name}` was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_empty_and_overlong_names() {
+        assert!(ProjName::parse("").is_err());
+        assert!(ProjName::parse(&"a".repeat(MAX_PROJ_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn rejects_names_docker_wont_tag() {
+        for name in ["App", "MY-APP", "app name", "app:latest", "app@1", "ä"] {
+            assert!(ProjName::parse(name).is_err(), "`{name}` was accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_separators_at_the_ends_or_repeated() {
+        for name in ["-app", "app-", "_app", "app_", ".app", "app.", "a--b", "a._b", "a..b"] {
+            assert!(ProjName::parse(name).is_err(), "`{name}` was accepted");
+        }
+    }
+
+    #[test]
+    fn resolves_relative_paths_inside_the_project() {
+        let proj = Path::new("/projects/app");
+        assert_eq!(resolve_proj_path(proj, "src/main.rs").unwrap(), proj.join("src/main.rs"));
+        assert_eq!(resolve_proj_path(proj, "Dockerfile").unwrap(), proj.join("Dockerfile"));
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_project() {
+        let proj = Path::new("/projects/app");
+        for path in ["", "..", "../other/file", "src/../../other", "/etc/passwd", "./file"] {
+            assert!(resolve_proj_path(proj, path).is_err(), "`{path}` was accepted");
+        }
     }
 }
@@ -0,0 +1,282 @@
+use crate::{config_utils, proj_utils::ProjName};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+use tynkerbase_universal::crypt_utils::aes_utils::{AesEncryption, AesMsg};
+
+const MAX_SECRET_NAME_LEN: usize = 128;
+const SECRET_MOUNT_DIR: &str = "/run/secrets";
+
+#[derive(Serialize, Deserialize)]
+struct StoredSecret {
+    name: String,
+    as_file: bool,
+    value: AesMsg,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SecretInfo {
+    pub name: String,
+    pub as_file: bool,
+}
+
+/// What `docker run` needs to expose a project's secrets to its container.
+#[derive(Debug, Default)]
+pub struct SecretInjection {
+    pub env_file: Option<String>,
+    // [host path, container path], mounted read only
+    pub mounts: Vec<[String; 2]>,
+}
+
+impl SecretInjection {
+    /// Removes the env file once the container has been created, docker has read it by then.
+    pub fn cleanup(&self) {
+        if let Some(path) = &self.env_file {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn write_private(path: &str, contents: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| anyhow!("Failed to open `{path}` -> {e}"))?;
+    file.write_all(contents)
+        .map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))
+}
+
+fn ensure_dir(dir: &str) -> Result<()> {
+    if !Path::new(dir).exists() {
+        fs::create_dir_all(dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+    Ok(())
+}
+
+// Secrets are encrypted with the node's API key, so reading the secret stores alone isn't enough
+// to decrypt them. When the key changes they are re-encrypted, see `reencrypt_all`.
+fn get_encryption(apikey: &str) -> AesEncryption {
+    AesEncryption::from_tyb_apikey(apikey)
+}
+
+fn get_store_path(proj_name: &ProjName) -> String {
+    format!("{}/data/secrets/{}.bin", config_utils::root_dir(), proj_name)
+}
+
+fn load_store(proj_name: &ProjName) -> Result<Vec<StoredSecret>> {
+    match fs::read(get_store_path(proj_name)) {
+        Ok(bin) => bincode::deserialize(&bin)
+            .map_err(|e| anyhow!("Secret store for `{proj_name}` is corrupted -> {e}")),
+        _ => Ok(vec![]),
+    }
+}
+
+fn save_store(proj_name: &ProjName, secrets: &[StoredSecret]) -> Result<()> {
+    ensure_dir(&format!("{}/data/secrets", config_utils::root_dir()))?;
+    let bin = bincode::serialize(secrets).map_err(|e| anyhow!("Failed to serialize secrets -> {e}"))?;
+    write_private(&get_store_path(proj_name), &bin)
+}
+
+/// Secret names double as environment variable and file names, so they must be valid as both.
+pub fn validate_secret_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_SECRET_NAME_LEN
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(anyhow!(
+            "Secret name `{}` is invalid, use up to {} letters, digits and `_` (not starting with a digit)",
+            name,
+            MAX_SECRET_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+pub fn set_secret(proj_name: &ProjName, name: &str, value: &str, as_file: bool, apikey: &str) -> Result<()> {
+    validate_secret_name(name)?;
+    if !as_file && value.contains('\n') {
+        return Err(anyhow!(
+            "Secret `{}` spans multiple lines, which environment variables can't hold. Store it as a file instead",
+            name
+        ));
+    }
+
+    let aes = get_encryption(apikey);
+    let mut msg = AesMsg::from_str(value);
+    aes.encrypt(&mut msg)
+        .map_err(|e| anyhow!("Failed to encrypt secret -> {}", e))?;
+
+    let mut secrets = load_store(proj_name)?;
+    secrets.retain(|s| s.name != name);
+    secrets.push(StoredSecret {
+        name: name.to_string(),
+        as_file,
+        value: msg,
+    });
+    save_store(proj_name, &secrets)
+}
+
+pub fn list_secrets(proj_name: &ProjName) -> Result<Vec<SecretInfo>> {
+    let secrets = load_store(proj_name)?;
+    Ok(secrets
+        .iter()
+        .map(|s| SecretInfo {
+            name: s.name.clone(),
+            as_file: s.as_file,
+        })
+        .collect())
+}
+
+pub fn delete_secret(proj_name: &ProjName, name: &str) -> Result<()> {
+    let mut secrets = load_store(proj_name)?;
+    let before = secrets.len();
+    secrets.retain(|s| s.name != name);
+    if secrets.len() == before {
+        return Err(anyhow!("Secret `{}` does not exist", name));
+    }
+
+    let mounted = format!("{}/{}", get_run_dir(proj_name), name);
+    if Path::new(&mounted).exists() {
+        let _ = fs::remove_file(&mounted);
+    }
+    save_store(proj_name, &secrets)
+}
+
+/// Removes every secret stored for a project.
+pub fn delete_all(proj_name: &ProjName) -> Result<()> {
+    let path = get_store_path(proj_name);
+    if Path::new(&path).exists() {
+        fs::remove_file(&path).map_err(|e| anyhow!("Failed to remove `{path}` -> {e}"))?;
+    }
+    let run_dir = get_run_dir(proj_name);
+    if Path::new(&run_dir).exists() {
+        fs::remove_dir_all(&run_dir).map_err(|e| anyhow!("Failed to remove `{run_dir}` -> {e}"))?;
+    }
+    Ok(())
+}
+
+fn get_run_dir(proj_name: &ProjName) -> String {
+    format!("{}/data/secret-run/{}", config_utils::root_dir(), proj_name)
+}
+
+/// Decrypts a project's secrets and writes them where `docker run` can pick them up.
+pub fn prepare_injection(proj_name: &ProjName, apikey: &str) -> Result<SecretInjection> {
+    let secrets = load_store(proj_name)?;
+    let mut injection = SecretInjection::default();
+    if secrets.is_empty() {
+        return Ok(injection);
+    }
+
+    let aes = get_encryption(apikey);
+    let run_dir = get_run_dir(proj_name);
+    ensure_dir(&run_dir)?;
+
+    let mut env_lines = String::new();
+    for secret in secrets {
+        let mut msg = secret.value;
+        aes.decrypt(&mut msg)
+            .map_err(|e| anyhow!("Failed to decrypt secret `{}` -> {}", secret.name, e))?;
+        let value = msg
+            .extract_str()
+            .map_err(|e| anyhow!("Failed to decrypt secret `{}` -> {}", secret.name, e))?;
+
+        if secret.as_file {
+            let host_path = format!("{run_dir}/{}", secret.name);
+            write_private(&host_path, value.as_bytes())?;
+            injection
+                .mounts
+                .push([host_path, format!("{SECRET_MOUNT_DIR}/{}", secret.name)]);
+        } else {
+            env_lines.push_str(&format!("{}={}\n", secret.name, value));
+        }
+    }
+
+    if !env_lines.is_empty() {
+        let env_file = format!("{run_dir}/.env");
+        write_private(&env_file, env_lines.as_bytes())?;
+        injection.env_file = Some(env_file);
+    }
+
+    Ok(injection)
+}
+
+/// Every project's secrets, decrypted and waiting to be encrypted with a new API key.
+pub struct StagedSecrets(Vec<(String, Vec<StoredSecret>)>);
+
+/// Decrypts every project's secrets with `apikey` without changing anything on disk, so a secret
+/// that can't be decrypted fails before the key is changed anywhere.
+pub fn decrypt_all(apikey: &str) -> Result<StagedSecrets> {
+    let dir = format!("{}/data/secrets", config_utils::root_dir());
+    let entries = match fs::read_dir(&dir) {
+        Ok(e) => e,
+        _ => return Ok(StagedSecrets(vec![])),
+    };
+
+    let aes = get_encryption(apikey);
+    let mut stores = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "bin") {
+            continue;
+        }
+        let path = path.to_string_lossy().to_string();
+        let bin = fs::read(&path).map_err(|e| anyhow!("Failed to read `{path}` -> {e}"))?;
+        let mut secrets: Vec<StoredSecret> =
+            bincode::deserialize(&bin).map_err(|e| anyhow!("Secret store `{path}` is corrupted -> {e}"))?;
+        for secret in secrets.iter_mut() {
+            aes.decrypt(&mut secret.value)
+                .map_err(|e| anyhow!("Failed to decrypt secret `{}` in `{path}` -> {}", secret.name, e))?;
+        }
+        stores.push((path, secrets));
+    }
+    Ok(StagedSecrets(stores))
+}
+
+impl StagedSecrets {
+    /// Encrypts the secrets with `apikey` and replaces the stores. Every store is written to a
+    /// temporary file before any is replaced, so a failed write leaves them all as they were.
+    /// Returns how many projects' secrets were re-encrypted.
+    pub fn commit(mut self, apikey: &str) -> Result<usize> {
+        let aes = get_encryption(apikey);
+        let mut tmp_paths = vec![];
+        let res = (|| {
+            for (path, secrets) in self.0.iter_mut() {
+                for secret in secrets.iter_mut() {
+                    aes.encrypt(&mut secret.value)
+                        .map_err(|e| anyhow!("Failed to encrypt secret `{}` -> {}", secret.name, e))?;
+                }
+                let bin = bincode::serialize(secrets).map_err(|e| anyhow!("Failed to serialize secrets -> {e}"))?;
+                let tmp_path = format!("{path}.tmp");
+                write_private(&tmp_path, &bin)?;
+                tmp_paths.push(tmp_path);
+            }
+            Ok(())
+        })();
+        if let Err(e) = res {
+            for tmp_path in &tmp_paths {
+                let _ = fs::remove_file(tmp_path);
+            }
+            return Err(e);
+        }
+
+        for ((path, _), tmp_path) in self.0.iter().zip(&tmp_paths) {
+            fs::rename(tmp_path, path).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))?;
+        }
+        Ok(self.0.len())
+    }
+}
+
+/// Re-encrypts every stored secret after the API key changed. Returns how many projects'
+/// secrets were re-encrypted.
+pub fn reencrypt_all(old_apikey: &str, new_apikey: &str) -> Result<usize> {
+    decrypt_all(old_apikey)?.commit(new_apikey)
+}
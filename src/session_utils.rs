@@ -0,0 +1,160 @@
+use crate::{
+    config_utils,
+    control_plane::{self, LoginResult},
+    global_state::{GlobalState, TsGlobalState},
+    ngrok_utils, secret_utils,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tynkerbase_universal::crypt_utils::{self, aes_utils, hash_utils};
+
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+const RESYNC_KEY_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Everything needed to bring the agent up without talking to the control server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedSession {
+    pub email: String,
+    pub pass_sha256: String,
+    pub pass_sha384: String,
+    pub tyb_apikey: String,
+    pub node_id: String,
+    pub name: String,
+}
+
+/// Key the session cache is encrypted with. It is derived from the password, which is never stored.
+pub fn derive_session_key(password: &str) -> String {
+    hash_utils::sha384(&format!("tyb-session-v1:{}", password))
+}
+
+pub fn get_session_path() -> String {
+    format!("{}/data/session.bin", config_utils::root_dir())
+}
+
+/// Deletes the cached session, returns whether there was one.
+pub fn clear_session() -> Result<bool> {
+    let path = get_session_path();
+    if !Path::new(&path).exists() {
+        return Ok(false);
+    }
+    fs::remove_file(&path).map_err(|e| anyhow!("Failed to remove `{path}` -> {e}"))?;
+    Ok(true)
+}
+
+pub fn save_session(session: &CachedSession, session_key: &str) -> Result<()> {
+    let dir = format!("{}/data", config_utils::root_dir());
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    }
+
+    let json = serde_json::to_string(session).map_err(|e| anyhow!("Failed to serialize session -> {e}"))?;
+    let aes = aes_utils::AesEncryption::from_tyb_apikey(session_key);
+    let mut msg = aes_utils::AesMsg::from_str(&json);
+    aes.encrypt(&mut msg)
+        .map_err(|e| anyhow!("Failed to encrypt session -> {}", e))?;
+    let bin = bincode::serialize(&msg).map_err(|e| anyhow!("Failed to serialize session -> {e}"))?;
+
+    fs::write(get_session_path(), bin).map_err(|e| anyhow!("Failed to write session -> {e}"))
+}
+
+/// Caches the session currently held in the global state.
+pub fn cache_session(state: &GlobalState) -> Result<()> {
+    let session_key = state
+        .session_key
+        .as_ref()
+        .ok_or_else(|| anyhow!("No session key, was the agent logged in?"))?;
+    let session = CachedSession {
+        email: state.email.clone().unwrap_or_default(),
+        pass_sha256: state.pass_sha256.clone().unwrap_or_default(),
+        pass_sha384: state.pass_sha384.clone().unwrap_or_default(),
+        tyb_apikey: state.tyb_apikey.clone().unwrap_or_default(),
+        node_id: state.node_id.clone().unwrap_or_default(),
+        name: state.name.clone().unwrap_or_default(),
+    };
+    save_session(&session, session_key)
+}
+
+pub fn load_session(session_key: &str) -> Result<CachedSession> {
+    let bin = fs::read(get_session_path())
+        .map_err(|e| anyhow!("No cached session found, log in while online first -> {e}"))?;
+    let mut msg: aes_utils::AesMsg =
+        bincode::deserialize(&bin).map_err(|e| anyhow!("Cached session is corrupted -> {e}"))?;
+
+    let aes = aes_utils::AesEncryption::from_tyb_apikey(session_key);
+    aes.decrypt(&mut msg)
+        .map_err(|_| anyhow!("Failed to decrypt cached session, is the password correct?"))?;
+    let json = msg
+        .extract_str()
+        .map_err(|_| anyhow!("Failed to decrypt cached session, is the password correct?"))?;
+
+    serde_json::from_str(&json).map_err(|e| anyhow!("Cached session is corrupted -> {e}"))
+}
+
+/// Runs while the agent is offline, retrying the login until the control plane is reachable again.
+/// Once it is, the API key is refreshed, the session cache updated and (for public nodes) the
+/// tunnel's address registered.
+pub async fn resync_loop(gstate: &'static TsGlobalState, public: bool) {
+    loop {
+        tokio::time::sleep(RESYNC_INTERVAL).await;
+
+        let lock = gstate.read().await;
+        let (email, pass_sha256, pass_sha384) = match (&lock.email, &lock.pass_sha256, &lock.pass_sha384) {
+            (Some(e), Some(p256), Some(p384)) => (e.clone(), p256.clone(), p384.clone()),
+            _ => return,
+        };
+        drop(lock);
+
+        let salt = match control_plane::get().login(&email, &pass_sha256).await {
+            LoginResult::Success(salt) => salt,
+            LoginResult::Unauthorized => {
+                log::error!("Control server rejected the cached credentials, staying offline.");
+                return;
+            }
+            LoginResult::Unreachable(e) => {
+                log::debug!("Control server still unreachable -> {}", e);
+                continue;
+            }
+        };
+
+        let mut lock = gstate.write().await;
+        let new_key = crypt_utils::gen_apikey(&pass_sha384, &salt);
+        // Secrets are encrypted with the API key, they have to follow it before it's swapped. If
+        // they can't, the old key is kept and the next attempt tries again.
+        if let Some(old_key) = lock.tyb_apikey.as_ref().filter(|k| **k != new_key) {
+            if let Err(e) = secret_utils::reencrypt_all(old_key, &new_key) {
+                log::error!("Failed to re-encrypt secrets for the new API key, staying offline -> {}", e);
+                continue;
+            }
+        }
+        // If the key was rotated while we were offline, keep accepting the cached one for a while
+        if let Some(old_key) = lock.tyb_apikey.replace(new_key.clone()) {
+            if old_key != new_key {
+                lock.prev_apikey = Some((old_key, Instant::now() + RESYNC_KEY_GRACE));
+            }
+        }
+        lock.offline = false;
+        let public_addr = lock.public_addr.clone();
+        let node_id = lock.node_id.clone().unwrap_or_default();
+        let name = lock.name.clone().unwrap_or_default();
+        if let Err(e) = cache_session(&lock) {
+            log::warn!("Failed to update cached session -> {}", e);
+        }
+        drop(lock);
+
+        if public {
+            if let Some(addr) = public_addr {
+                if let Err(e) = ngrok_utils::register_addr(&email, &pass_sha256, &node_id, &name, &addr).await {
+                    log::error!("Failed to register public address with the control server -> {}", e);
+                }
+            }
+        }
+
+        log::info!("Reconnected to the control server.");
+        return;
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{
+    config_utils::{self, ContainerPolicy},
+    docker_utils,
+    global_state::TsGlobalState,
+    ngrok_utils, systemd_utils,
+};
+use std::{io::Write, time::Duration};
+
+/// Cleans up after the agent when it is asked to stop (Ctrl-C, SIGTERM, ...). Every step is
+/// attempted even if an earlier one fails, and the whole thing is bounded by the configured timeout.
+pub async fn shutdown(gstate: &'static TsGlobalState) {
+    let policy = &config_utils::get().shutdown;
+    systemd_utils::notify_stopping();
+    log::info!("Shutting down...");
+
+    let steps = async {
+        if policy.deregister {
+            deregister(gstate).await;
+        }
+
+        if let Err(e) = ngrok_utils::stop_ngrok() {
+            log::warn!("Failed to stop the tunnel -> {}", e);
+        }
+
+        if policy.containers == ContainerPolicy::Stop {
+            stop_containers().await;
+        }
+    };
+
+    let timeout = Duration::from_secs(policy.timeout_secs);
+    if tokio::time::timeout(timeout, steps).await.is_err() {
+        log::warn!("Shutdown did not finish within {:?}, exiting anyway.", timeout);
+        // Whatever happens, don't leave the tunnel behind
+        let _ = ngrok_utils::stop_ngrok();
+    }
+
+    log::logger().flush();
+    let _ = std::io::stdout().flush();
+}
+
+async fn deregister(gstate: &'static TsGlobalState) {
+    let lock = gstate.read().await;
+    if lock.offline || lock.public_addr.is_none() {
+        return;
+    }
+    let (email, pass_sha256, node_id, name) = match (&lock.email, &lock.pass_sha256, &lock.node_id, &lock.name) {
+        (Some(e), Some(p), Some(id), Some(n)) => (e.clone(), p.clone(), id.clone(), n.clone()),
+        _ => return,
+    };
+    drop(lock);
+
+    match ngrok_utils::deregister_addr(&email, &pass_sha256, &node_id, &name).await {
+        Ok(_) => log::info!("Removed this node's public address from the control server."),
+        Err(e) => log::warn!("Failed to deregister from the control server -> {}", e),
+    }
+}
+
+async fn stop_containers() {
+    let containers = match docker_utils::list_running_managed_containers().await {
+        Ok(c) => c,
+        Err(e) => {
+            log::warn!("Failed to list containers to stop -> {}", e);
+            return;
+        }
+    };
+
+    let handles: Vec<_> = containers
+        .into_iter()
+        .map(|c| tokio::spawn(async move { (docker_utils::pause_container(&c).await, c) }))
+        .collect();
+    for h in handles {
+        match h.await {
+            Ok((Ok(_), c)) => log::info!("Stopped container `{}`", c),
+            Ok((Err(e), c)) => log::warn!("Failed to stop container `{}` -> {}", c, e),
+            Err(e) => log::warn!("Failed to stop container -> {}", e),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+/*
+Request signing lets clients authenticate without sending their API key on the wire.
+
+A signed request carries the following headers:
+    tyb-timestamp       unix time (seconds) at which the request was signed
+    tyb-nonce           random string, unique per request
+    tyb-content-sha256  hex sha256 of the request body (of an empty body if there is none)
+    tyb-key-name        (optional) name of the scoped token used to sign, omitted for the node's API key
+    tyb-signature       hex HMAC-SHA256 of the canonical request
+
+The canonical request is the newline separated string
+    METHOD \n PATH_AND_QUERY \n CONTENT_SHA256 \n TIMESTAMP \n NONCE
+
+and the HMAC key is `hash_utils::sha256("tyb-signing-v1:" + hash_utils::sha256(key))`.
+*/
+
+use crate::auth_utils::now_secs;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tynkerbase_universal::crypt_utils::hash_utils;
+
+pub const SIGNATURE_HEADER: &str = "tyb-signature";
+pub const TIMESTAMP_HEADER: &str = "tyb-timestamp";
+pub const NONCE_HEADER: &str = "tyb-nonce";
+pub const CONTENT_SHA256_HEADER: &str = "tyb-content-sha256";
+pub const KEY_NAME_HEADER: &str = "tyb-key-name";
+
+// How far a request's timestamp may drift from the agent's clock
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+const MAX_NONCE_LEN: usize = 128;
+
+/// Everything a client put in the signing headers of a request.
+pub struct SignedHeaders<'a> {
+    pub signature: &'a str,
+    pub timestamp: &'a str,
+    pub nonce: &'a str,
+    pub content_sha256: &'a str,
+    pub key_name: Option<&'a str>,
+}
+
+/// Remembers the nonces seen within the allowed clock skew so that a captured request
+/// can't be replayed.
+#[derive(Debug, Default)]
+pub struct NonceCache {
+    seen: HashMap<String, u64>,
+}
+
+impl NonceCache {
+    /// Returns false if the nonce was already used.
+    fn insert(&mut self, nonce: &str, timestamp: u64, now: u64) -> bool {
+        self.seen
+            .retain(|_, ts| now.saturating_sub(*ts) <= 2 * MAX_CLOCK_SKEW_SECS);
+        if self.seen.contains_key(nonce) {
+            return false;
+        }
+        self.seen.insert(nonce.to_string(), timestamp);
+        true
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Derives the HMAC key from the sha256 of an API key or scoped token.
+pub fn derive_signing_key(key_sha256: &str) -> String {
+    hash_utils::sha256(&format!("tyb-signing-v1:{}", key_sha256))
+}
+
+pub fn canonical_request(
+    method: &str,
+    path_and_query: &str,
+    content_sha256: &str,
+    timestamp: &str,
+    nonce: &str,
+) -> String {
+    format!("{method}\n{path_and_query}\n{content_sha256}\n{timestamp}\n{nonce}")
+}
+
+/// Checks the signature, timestamp and nonce of a request signed with the key whose
+/// sha256 is `key_sha256`.
+pub fn verify(
+    headers: &SignedHeaders,
+    method: &str,
+    path_and_query: &str,
+    key_sha256: &str,
+    nonces: &mut NonceCache,
+) -> Result<()> {
+    let timestamp: u64 = headers
+        .timestamp
+        .parse()
+        .map_err(|_| anyhow!("`{}` is not a valid timestamp", headers.timestamp))?;
+    let now = now_secs();
+    if now.abs_diff(timestamp) > MAX_CLOCK_SKEW_SECS {
+        return Err(anyhow!("Request timestamp is stale"));
+    }
+
+    if headers.nonce.is_empty() || headers.nonce.len() > MAX_NONCE_LEN {
+        return Err(anyhow!("Nonce must be between 1 and {} characters", MAX_NONCE_LEN));
+    }
+
+    let signature =
+        hex::decode(headers.signature).map_err(|e| anyhow!("Signature is not valid hex -> {e}"))?;
+
+    let canonical = canonical_request(
+        method,
+        path_and_query,
+        headers.content_sha256,
+        headers.timestamp,
+        headers.nonce,
+    );
+    let mut mac = Hmac::<Sha256>::new_from_slice(derive_signing_key(key_sha256).as_bytes())
+        .map_err(|e| anyhow!("Failed to create HMAC -> {e}"))?;
+    mac.update(canonical.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| anyhow!("Signature does not match"))?;
+
+    // Only remember the nonce once the signature checks out so that garbage requests
+    // can't fill up the cache
+    if !nonces.insert(headers.nonce, timestamp, now) {
+        return Err(anyhow!("Nonce has already been used"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_SHA256: &str = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8";
+    const METHOD: &str = "POST";
+    const PATH: &str = "/files/proj/add-files-to-proj?name=app";
+
+    fn sign(content_sha256: &str, timestamp: &str, nonce: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(derive_signing_key(KEY_SHA256).as_bytes()).unwrap();
+        mac.update(canonical_request(METHOD, PATH, content_sha256, timestamp, nonce).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn headers<'a>(signature: &'a str, timestamp: &'a str, nonce: &'a str, content_sha256: &'a str) -> SignedHeaders<'a> {
+        SignedHeaders {
+            signature,
+            timestamp,
+            nonce,
+            content_sha256,
+            key_name: None,
+        }
+    }
+
+    #[test]
+    fn accepts_a_correct_signature() {
+        let (body, timestamp) = (sha256_hex(b"body"), now_secs().to_string());
+        let signature = sign(&body, &timestamp, "nonce-1");
+        let mut nonces = NonceCache::default();
+        verify(&headers(&signature, &timestamp, "nonce-1", &body), METHOD, PATH, KEY_SHA256, &mut nonces).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_stale_timestamp() {
+        let body = sha256_hex(b"body");
+        for timestamp in [now_secs() - MAX_CLOCK_SKEW_SECS - 10, now_secs() + MAX_CLOCK_SKEW_SECS + 10] {
+            let timestamp = timestamp.to_string();
+            let signature = sign(&body, &timestamp, "nonce-1");
+            let mut nonces = NonceCache::default();
+            let res = verify(&headers(&signature, &timestamp, "nonce-1", &body), METHOD, PATH, KEY_SHA256, &mut nonces);
+            assert!(res.unwrap_err().to_string().contains("stale"));
+        }
+    }
+
+    #[test]
+    fn rejects_a_replayed_nonce() {
+        let (body, timestamp) = (sha256_hex(b"body"), now_secs().to_string());
+        let signature = sign(&body, &timestamp, "nonce-1");
+        let signed = headers(&signature, &timestamp, "nonce-1", &body);
+        let mut nonces = NonceCache::default();
+        verify(&signed, METHOD, PATH, KEY_SHA256, &mut nonces).unwrap();
+        let res = verify(&signed, METHOD, PATH, KEY_SHA256, &mut nonces);
+        assert!(res.unwrap_err().to_string().contains("already been used"));
+    }
+
+    #[test]
+    fn rejects_a_tampered_body_hash() {
+        let timestamp = now_secs().to_string();
+        let signature = sign(&sha256_hex(b"body"), &timestamp, "nonce-1");
+        let tampered = sha256_hex(b"other body");
+        let mut nonces = NonceCache::default();
+        let res = verify(&headers(&signature, &timestamp, "nonce-1", &tampered), METHOD, PATH, KEY_SHA256, &mut nonces);
+        assert!(res.unwrap_err().to_string().contains("does not match"));
+        // A rejected request doesn't use up its nonce
+        assert!(nonces.insert("nonce-1", now_secs(), now_secs()));
+    }
+
+    #[test]
+    fn rejects_a_signature_for_another_path() {
+        let (body, timestamp) = (sha256_hex(b"body"), now_secs().to_string());
+        let signature = sign(&body, &timestamp, "nonce-1");
+        let mut nonces = NonceCache::default();
+        let res = verify(
+            &headers(&signature, &timestamp, "nonce-1", &body),
+            METHOD,
+            "/files/proj/delete-proj?name=app",
+            KEY_SHA256,
+            &mut nonces,
+        );
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,193 @@
+/*
+Earlier versions of each project, so a broken upload can be rolled back. Every successful
+upload is copied to
+
+    {projects_dir}/.snapshots/{project}/{version}/         the project's files
+    {projects_dir}/.snapshots/{project}/{version}.json     `Snapshot`, written once the copy is complete
+
+and only the newest `snapshots.keep` versions are kept. A version's id is its creation time (in
+milliseconds) followed by the start of its content hash, so ids sort by age.
+*/
+
+use crate::{
+    auth_utils, config_utils,
+    proj_utils::{self, ProjName},
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const VERSION_HASH_LEN: usize = 12;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: String,
+    pub created_at: u64,
+    // sha256 over every file's path and sha256, see `content_hash`
+    pub sha256: String,
+    pub files: usize,
+    pub size_bytes: u64,
+}
+
+fn get_snapshots_dir(name: &ProjName) -> PathBuf {
+    PathBuf::from(config_utils::projects_dir())
+        .join(".snapshots")
+        .join(name.as_str())
+}
+
+// Versions end up in paths, so only ids the agent could have generated are accepted
+fn get_version_path(name: &ProjName, version: &str) -> Result<PathBuf> {
+    if version.is_empty() || !version.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(anyhow!("Version `{}` of project `{}` does not exist", version, name));
+    }
+    Ok(get_snapshots_dir(name).join(version))
+}
+
+// Identifies a directory's contents, independent of timestamps and file order
+fn content_hash(path: &Path) -> Result<(String, usize, u64)> {
+    let manifest = proj_utils::dir_manifest(path)?;
+    let mut hasher = Sha256::new();
+    for (file, hash) in &manifest {
+        hasher.update(file.as_bytes());
+        hasher.update([0]);
+        hasher.update(hash.as_bytes());
+        hasher.update([b'\n']);
+    }
+    let size_bytes = manifest
+        .keys()
+        .filter_map(|f| fs::metadata(path.join(f)).ok())
+        .map(|m| m.len())
+        .sum();
+    Ok((hex::encode(hasher.finalize()), manifest.len(), size_bytes))
+}
+
+/// Every complete snapshot of the project, newest first.
+pub fn list(name: &ProjName) -> Result<Vec<Snapshot>> {
+    let dir = get_snapshots_dir(name);
+    let entries = match fs::read_dir(&dir) {
+        Ok(e) => e,
+        _ => return Ok(vec![]),
+    };
+
+    let mut snapshots = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "json") || !path.with_extension("").is_dir() {
+            continue;
+        }
+        let text = fs::read_to_string(&path).map_err(|e| anyhow!("Failed to read `{}` -> {e}", path.display()))?;
+        match serde_json::from_str::<Snapshot>(&text) {
+            Ok(s) => snapshots.push(s),
+            Err(e) => log::warn!("Skipping corrupted snapshot `{}` -> {}", path.display(), e),
+        }
+    }
+    snapshots.sort_by(|a, b| b.version.cmp(&a.version));
+    Ok(snapshots)
+}
+
+/// Copies the project's current files into a new snapshot, unless they're the same as the
+/// newest snapshot's. Older snapshots beyond `snapshots.keep` are then removed.
+pub fn take(name: &ProjName) -> Result<Option<Snapshot>> {
+    let keep = config_utils::get().snapshots.keep;
+    if keep == 0 {
+        return Ok(None);
+    }
+
+    let proj_path = name.path();
+    let (sha256, files, size_bytes) = content_hash(&proj_path)?;
+    if list(name)?.first().is_some_and(|s| s.sha256 == sha256) {
+        return Ok(None);
+    }
+
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let snapshot = Snapshot {
+        version: format!("{:013}-{}", now_ms, &sha256[..VERSION_HASH_LEN]),
+        created_at: auth_utils::now_secs(),
+        sha256,
+        files,
+        size_bytes,
+    };
+
+    // Copy then rename so a half written snapshot is never listed. Uploads to a project don't
+    // overlap, so any unfinished snapshot was left behind by an earlier run.
+    let path = get_version_path(name, &snapshot.version)?;
+    let tmp_path = path.with_extension("tmp");
+    if let Ok(entries) = fs::read_dir(get_snapshots_dir(name)) {
+        for entry in entries.flatten() {
+            let p = entry.path();
+            if p.is_dir() && (p.extension().is_some_and(|e| e == "tmp") || !p.with_extension("json").exists()) {
+                let _ = fs::remove_dir_all(&p);
+            }
+        }
+    }
+    if let Err(e) = proj_utils::copy_dir(&proj_path, &tmp_path) {
+        let _ = fs::remove_dir_all(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, &path).map_err(|e| anyhow!("Failed to write `{}` -> {e}", path.display()))?;
+    let json = serde_json::to_string_pretty(&snapshot).map_err(|e| anyhow!("Failed to serialize snapshot -> {e}"))?;
+    let json_path = path.with_extension("json");
+    fs::write(&json_path, json).map_err(|e| anyhow!("Failed to write `{}` -> {e}", json_path.display()))?;
+
+    prune(name, Some(keep), None)?;
+    Ok(Some(snapshot))
+}
+
+/// Replaces the project's files with those of `version`, checking the copy against the
+/// snapshot's content hash before it's swapped in.
+pub fn restore(name: &ProjName, version: &str) -> Result<Snapshot> {
+    let snapshot = list(name)?
+        .into_iter()
+        .find(|s| s.version == version)
+        .ok_or_else(|| anyhow!("Version `{}` of project `{}` does not exist", version, name))?;
+    let path = get_version_path(name, version)?;
+
+    proj_utils::stage_upload(name, false, |staging_path| {
+        proj_utils::copy_dir(&path, staging_path)?;
+        let (sha256, _, _) = content_hash(staging_path)?;
+        if sha256 != snapshot.sha256 {
+            return Err(anyhow!("Version `{}` is corrupted, its files don't match its hash", version));
+        }
+        Ok(())
+    })?;
+    Ok(snapshot)
+}
+
+/// Removes every snapshot beyond the newest `keep` and every one older than `older_than_secs`.
+/// Returns the removed versions.
+pub fn prune(name: &ProjName, keep: Option<usize>, older_than_secs: Option<u64>) -> Result<Vec<String>> {
+    let cutoff = older_than_secs.map(|s| auth_utils::now_secs().saturating_sub(s));
+    let mut removed = vec![];
+    for (i, snapshot) in list(name)?.into_iter().enumerate() {
+        let too_many = keep.is_some_and(|k| i >= k);
+        let too_old = cutoff.is_some_and(|c| snapshot.created_at < c);
+        if !too_many && !too_old {
+            continue;
+        }
+
+        // The metadata goes first, without it the snapshot is no longer listed
+        let path = get_version_path(name, &snapshot.version)?;
+        let json_path = path.with_extension("json");
+        fs::remove_file(&json_path).map_err(|e| anyhow!("Failed to remove `{}` -> {e}", json_path.display()))?;
+        fs::remove_dir_all(&path).map_err(|e| anyhow!("Failed to remove `{}` -> {e}", path.display()))?;
+        removed.push(snapshot.version);
+    }
+    Ok(removed)
+}
+
+/// Removes every snapshot of the project.
+pub fn delete_all(name: &ProjName) -> Result<()> {
+    let dir = get_snapshots_dir(name);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).map_err(|e| anyhow!("Failed to remove `{}` -> {e}", dir.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use crate::{config_utils, global_state::TsGlobalState, input_utils::StartupInputs};
+use anyhow::{anyhow, Result};
+use sd_notify::NotifyState;
+use std::{
+    env, fs,
+    path::Path,
+    process::Command,
+    time::Duration,
+};
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+// systemd restarts the agent if it doesn't hear from the watchdog for this long
+const WATCHDOG_SECS: u64 = 30;
+
+/// Each instance gets its own unit so they can be installed side by side.
+pub fn unit_name() -> String {
+    match config_utils::instance() {
+        Some(name) => format!("tynkerbase-agent-{name}.service"),
+        None => "tynkerbase-agent.service".to_string(),
+    }
+}
+
+/// Renders a unit that runs `run` with the same options the installer was called with.
+pub fn render_unit(inputs: &StartupInputs) -> Result<String> {
+    let exe = env::current_exe().map_err(|e| anyhow!("Failed to find the agent's executable -> {e}"))?;
+    let exe = exe
+        .to_str()
+        .ok_or_else(|| anyhow!("Path of the agent's executable is not valid UTF-8"))?;
+
+    // A service can't answer prompts, so everything has to come from the credentials file
+    let credentials = inputs
+        .flag("--credentials")
+        .map(|c| c.to_string())
+        .or_else(|| env::var("TYB_CREDENTIALS_FILE").ok())
+        .ok_or_else(|| {
+            anyhow!("The service needs a credentials file to log in, pass it with `--credentials <path>`")
+        })?;
+
+    let mut args = vec!["run".to_string(), "--non-interactive".to_string()];
+    let mut add_path = |flag: &str, path: &str| -> Result<()> {
+        let path = fs::canonicalize(path).map_err(|e| anyhow!("Failed to resolve `{path}` -> {e}"))?;
+        args.push(flag.to_string());
+        args.push(format!("\"{}\"", path.display()));
+        Ok(())
+    };
+    add_path("--credentials", &credentials)?;
+    if let Some(config) = inputs.flag("--config").map(|c| c.to_string()).or_else(|| env::var("TYB_CONFIG").ok()) {
+        add_path("--config", &config)?;
+    }
+    if let Some(name) = config_utils::instance() {
+        args.push("--instance".to_string());
+        args.push(name.to_string());
+    }
+    for switch in ["--priv", "--mtls"] {
+        if inputs.has_switch(switch) {
+            args.push(switch.to_string());
+        }
+    }
+
+    Ok(format!(
+        "\
+[Unit]
+Description=TynkerBase Agent{description}
+After=network-online.target docker.service
+Wants=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart=\"{exe}\" {args}
+Restart=on-failure
+RestartSec=5
+WatchdogSec={WATCHDOG_SECS}
+# Logging in and bringing up the tunnel can take a while
+TimeoutStartSec=120
+
+[Install]
+WantedBy=multi-user.target
+",
+        args = args.join(" "),
+        description = config_utils::instance().map(|n| format!(" ({n})")).unwrap_or_default()
+    ))
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    let output = Command::new("systemctl")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Failed to run systemctl -> {e}"))?;
+
+    if !output.status.success() {
+        let err = String::from_utf8(output.stderr).unwrap_or("Unable to extract stderr".to_string());
+        return Err(anyhow!("`systemctl {}` failed -> {}", args.join(" "), err));
+    }
+    Ok(())
+}
+
+/// Writes the unit, then enables and starts the service.
+pub fn install_unit(unit: &str) -> Result<String> {
+    let unit_name = unit_name();
+    let path = format!("{UNIT_DIR}/{unit_name}");
+    if !Path::new(UNIT_DIR).exists() {
+        return Err(anyhow!("`{UNIT_DIR}` does not exist, is systemd running on this machine?"));
+    }
+    fs::write(&path, unit).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            return anyhow!("Permission denied writing `{path}`, re-run with `sudo`");
+        }
+        anyhow!("Failed to write `{path}` -> {e}")
+    })?;
+
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", &unit_name])?;
+    Ok(path)
+}
+
+/// Tells systemd the agent is up. Does nothing when not started by systemd.
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        log::warn!("Failed to notify systemd -> {}", e);
+    }
+}
+
+pub fn notify_stopping() {
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+}
+
+pub fn notify_status(status: &str) {
+    let _ = sd_notify::notify(false, &[NotifyState::Status(status)]);
+}
+
+/// Pings the systemd watchdog for as long as the agent is responsive, i.e. the global state
+/// can still be locked. If the agent deadlocks the pings stop and systemd restarts it.
+pub async fn watchdog_loop(gstate: &'static TsGlobalState) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let interval = Duration::from_micros(usec / 2);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        if tokio::time::timeout(interval, gstate.read()).await.is_err() {
+            log::warn!("Global state has been locked for {:?}, skipping watchdog ping.", interval);
+            continue;
+        }
+        let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::consts::AGENT_ROOTDIR_PATH;
+use crate::config_utils;
 use anyhow::{anyhow, Result};
 use std::{fs, path::Path, process::Command};
 
@@ -10,10 +10,10 @@ pub fn check_tls_cert(proj_root_path: &str) -> bool {
         && Path::new(&format!("{proj_root_path}/keys/tls-key.pem")).exists()
 }
 
-pub fn gen_tls_cert(proj_root_path: &str) -> Result<()> {
+pub fn gen_tls_cert(proj_root_path: &str, interactive: bool) -> Result<()> {
     clear_tls_cert(proj_root_path)?;
 
-    let cmd = vec![
+    let cmd = [
         "openssl".to_string(),
         "ecparam".to_string(),
         "-name".to_string(),
@@ -24,7 +24,11 @@ pub fn gen_tls_cert(proj_root_path: &str) -> Result<()> {
         format!("{}/keys/tls-key.pem", proj_root_path),
     ];
 
-    println!("Generating TLS private key...\nPlease answer the following questions to generate the certificate\n");
+    if interactive {
+        println!("Generating TLS private key...\nPlease answer the following questions to generate the certificate\n");
+    } else {
+        println!("Generating TLS private key...");
+    }
     let mut child = Command::new(&cmd[0]).args(&cmd[1..]).spawn().map_err(|e| {
         anyhow!("Failed to generate private key -> {e}\nMake sure you have openssl installed!")
     })?;
@@ -35,7 +39,7 @@ pub fn gen_tls_cert(proj_root_path: &str) -> Result<()> {
     println!("Finished generating private key.\n\nGenerating TLS certificate... ");
 
     // Command to generate the self-signed certificate
-    let cmd = vec![
+    let mut cmd = vec![
         "openssl".to_string(),
         "req".to_string(),
         "-x509".to_string(),
@@ -47,6 +51,11 @@ pub fn gen_tls_cert(proj_root_path: &str) -> Result<()> {
         "-days".to_string(),
         "36500".to_string(),
     ];
+    // Without a subject openssl asks for one on stdin
+    if !interactive {
+        cmd.push("-subj".to_string());
+        cmd.push("/CN=tynkerbase-agent".to_string());
+    }
 
     let mut child = Command::new(&cmd[0]).args(&cmd[1..]).spawn().map_err(|e| {
         anyhow!("Failed to generate private key -> {e}\nMake sure you have openssl installed!")
@@ -62,7 +71,7 @@ pub fn gen_tls_cert(proj_root_path: &str) -> Result<()> {
 
 pub fn clear_tls_cert(proj_root_path: &str) -> Result<()> {
     if !Path::new(&format!("{proj_root_path}/keys")).exists() {
-        fs::create_dir(&format!("{proj_root_path}/keys")).map_err(|e| {
+        fs::create_dir(format!("{proj_root_path}/keys")).map_err(|e| {
             anyhow!("Failed to create `keys` directory to hold the certificates -> {e}")
         })?;
     } else {
@@ -79,8 +88,127 @@ pub fn clear_tls_cert(proj_root_path: &str) -> Result<()> {
 }
 
 pub fn get_cert_paths() -> [String; 2] {
+    let tls = &config_utils::get().tls;
+    if let (Some(cert), Some(key)) = (&tls.cert_path, &tls.key_path) {
+        return [cert.clone(), key.clone()];
+    }
+    [
+        format!("{}/keys/tls-cert.pem", config_utils::root_dir()),
+        format!("{}/keys/tls-key.pem", config_utils::root_dir()),
+    ]
+}
+
+/// Whether the certificate is provided in the config rather than generated by the agent.
+pub fn uses_custom_cert() -> bool {
+    config_utils::get().tls.cert_path.is_some()
+}
+
+/// Whether the certificate at `path` expires within `secs` seconds (or already has).
+pub fn cert_expires_within(path: &str, secs: u64) -> Result<bool> {
+    let output = Command::new("openssl")
+        .args(["x509", "-checkend", &secs.to_string(), "-noout", "-in", path])
+        .output()
+        .map_err(|e| anyhow!("Failed to run openssl -> {e}"))?;
+
+    // openssl exits with 1 both when the cert expires and when it can't read it
+    match output.status.code() {
+        Some(0) => Ok(false),
+        Some(1) if output.stderr.is_empty() => Ok(true),
+        _ => {
+            let err = String::from_utf8(output.stderr).unwrap_or("Unable to extract stderr".to_string());
+            Err(anyhow!("Failed to read certificate `{path}` -> {err}"))
+        }
+    }
+}
+
+pub fn get_client_ca_paths() -> [String; 2] {
     [
-        format!("{}/keys/tls-cert.pem", AGENT_ROOTDIR_PATH),
-        format!("{}/keys/tls-key.pem", AGENT_ROOTDIR_PATH),
+        format!("{}/keys/client-ca-cert.pem", config_utils::root_dir()),
+        format!("{}/keys/client-ca-key.pem", config_utils::root_dir()),
     ]
 }
+
+pub fn check_client_ca() -> bool {
+    get_client_ca_paths().iter().all(|p| Path::new(p).exists())
+}
+
+fn run_openssl(args: &[&str]) -> Result<()> {
+    let output = Command::new("openssl")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("Failed to run openssl -> {e}\nMake sure you have openssl installed!"))?;
+
+    if !output.status.success() {
+        let err = String::from_utf8(output.stderr).unwrap_or("Unable to extract stderr".to_string());
+        return Err(anyhow!("openssl {} failed -> {}", args[0], err));
+    }
+    Ok(())
+}
+
+/// Generates the CA that signs the client certificates used for mutual TLS.
+pub fn gen_client_ca() -> Result<()> {
+    let keys_dir = format!("{}/keys", config_utils::root_dir());
+    if !Path::new(&keys_dir).exists() {
+        fs::create_dir_all(&keys_dir)
+            .map_err(|e| anyhow!("Failed to create `keys` directory -> {e}"))?;
+    }
+
+    let [cert_path, key_path] = get_client_ca_paths();
+    run_openssl(&["ecparam", "-name", "secp256r1", "-genkey", "-noout", "-out", &key_path])?;
+    run_openssl(&[
+        "req", "-x509", "-new",
+        "-key", &key_path,
+        "-out", &cert_path,
+        "-days", "3650",
+        "-subj", "/CN=tynkerbase-agent-client-ca",
+    ])?;
+    Ok(())
+}
+
+pub struct IssuedClientCert {
+    pub serial: String,
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+/// Issues a client certificate with common name `name`, signed by the client CA.
+/// The private key is returned to the caller and is not kept on disk.
+pub fn issue_client_cert(name: &str) -> Result<IssuedClientCert> {
+    let [ca_cert_path, ca_key_path] = get_client_ca_paths();
+
+    // Positive 127 bit serial, rendered the same way rocket renders serials of client certs
+    let mut serial_bytes: [u8; 16] = rand::random();
+    serial_bytes[0] &= 0x7f;
+    let serial = hex::encode(serial_bytes).trim_start_matches('0').to_string();
+
+    let work_dir = format!("{}/keys/issue-{}", config_utils::root_dir(), serial);
+    fs::create_dir_all(&work_dir).map_err(|e| anyhow!("Failed to create `{work_dir}` -> {e}"))?;
+    let key_path = format!("{work_dir}/client-key.pem");
+    let csr_path = format!("{work_dir}/client.csr");
+    let cert_path = format!("{work_dir}/client-cert.pem");
+    let subj = format!("/CN={}", name);
+    let set_serial = format!("0x{}", serial);
+
+    let res = (|| {
+        run_openssl(&["ecparam", "-name", "secp256r1", "-genkey", "-noout", "-out", &key_path])?;
+        run_openssl(&["req", "-new", "-key", &key_path, "-out", &csr_path, "-subj", &subj])?;
+        run_openssl(&[
+            "x509", "-req",
+            "-in", &csr_path,
+            "-CA", &ca_cert_path,
+            "-CAkey", &ca_key_path,
+            "-set_serial", &set_serial,
+            "-days", "365",
+            "-out", &cert_path,
+        ])?;
+
+        let cert_pem = fs::read_to_string(&cert_path)
+            .map_err(|e| anyhow!("Failed to read issued certificate -> {e}"))?;
+        let key_pem = fs::read_to_string(&key_path)
+            .map_err(|e| anyhow!("Failed to read issued key -> {e}"))?;
+        Ok(IssuedClientCert { serial: serial.clone(), cert_pem, key_pem })
+    })();
+
+    let _ = fs::remove_dir_all(&work_dir);
+    res
+}
@@ -0,0 +1,341 @@
+/*
+Chunked, resumable uploads for projects that don't fit in a single request body
+(`server.upload_limit_mb`). A client
+
+    1. starts a session with the total size, chunk size and (optionally) sha256 of the upload
+    2. puts every chunk by index together with the chunk's sha256, in any order and as often as needed
+    3. asks which chunks the agent already has after a dropped connection, and sends the rest
+    4. finalizes the session, which reassembles the chunks into the usual `BinaryPacket`
+
+Sessions live in `{root_dir}/uploads/{id}` and are removed once finalized, aborted or expired
+(`uploads.session_ttl_secs` after they were started). A project has at most one open session,
+and one is only started if the disk has room for the chunks, the reassembled upload and the
+unpacked files. Requests for the same session run one at a time.
+*/
+
+use crate::{auth_utils, config_utils, health_utils, signing_utils};
+use anyhow::{anyhow, Result};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{BufReader, Write},
+    collections::BTreeSet,
+    path::Path,
+    sync::Mutex,
+};
+use tynkerbase_universal::crypt_utils::BinaryPacket;
+
+const SESSION_ID_LEN: usize = 24;
+const BYTES_PER_MB: u64 = 1_000_000;
+
+// Held while looking for a project's open session and starting a new one
+static START_LOCK: Mutex<()> = Mutex::new(());
+
+// Sessions with a chunk being stored, or being finalized or aborted
+static SESSIONS_IN_USE: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// Marks a session as in use until dropped, so a retried request can't remove or replace chunks
+// another one is still reading
+struct SessionGuard(String);
+
+impl SessionGuard {
+    fn acquire(session: &UploadSession) -> Result<Self> {
+        let mut lock = SESSIONS_IN_USE
+            .lock()
+            .map_err(|e| anyhow!("Upload session lock poisoned -> {e}"))?;
+        if !lock.insert(session.id.clone()) {
+            return Err(anyhow!("A request for upload session `{}` is already in progress", session.id));
+        }
+        Ok(SessionGuard(session.id.clone()))
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut lock) = SESSIONS_IN_USE.lock() {
+            lock.remove(&self.0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub project: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    // sha256 of the whole upload, checked when the session is finalized
+    pub sha256: Option<String>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl UploadSession {
+    // The last chunk holds whatever is left over
+    fn expected_chunk_len(&self, index: u64) -> u64 {
+        match index + 1 == self.chunk_count {
+            true => self.size - self.chunk_size * index,
+            false => self.chunk_size,
+        }
+    }
+}
+
+/// A session together with the chunks the agent has received so far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadStatus {
+    pub session: UploadSession,
+    pub received: Vec<u64>,
+    pub missing: Vec<u64>,
+}
+
+fn get_uploads_dir() -> String {
+    format!("{}/uploads", config_utils::root_dir())
+}
+
+// Session ids end up in paths, so only ids the agent could have generated are accepted
+fn get_session_dir(id: &str) -> Result<String> {
+    if id.len() != SESSION_ID_LEN || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(anyhow!("Upload session `{}` does not exist", id));
+    }
+    Ok(format!("{}/{}", get_uploads_dir(), id))
+}
+
+fn get_chunk_path(dir: &str, index: u64) -> String {
+    format!("{dir}/chunk-{index}")
+}
+
+fn save_session(dir: &str, session: &UploadSession) -> Result<()> {
+    let json = serde_json::to_string_pretty(session)
+        .map_err(|e| anyhow!("Failed to serialize upload session -> {e}"))?;
+    let path = format!("{dir}/session.json");
+    fs::write(&path, json).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))
+}
+
+/// Loads a session that hasn't expired yet.
+pub fn load_session(id: &str) -> Result<UploadSession> {
+    let dir = get_session_dir(id)?;
+    let path = format!("{dir}/session.json");
+    let text = match fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(_) => return Err(anyhow!("Upload session `{}` does not exist", id)),
+    };
+    let session: UploadSession = serde_json::from_str(&text)
+        .map_err(|e| anyhow!("Upload session `{path}` is corrupted -> {e}"))?;
+    if session.expires_at <= auth_utils::now_secs() {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(anyhow!("Upload session `{}` does not exist", id));
+    }
+    Ok(session)
+}
+
+/// Removes every session that has expired, along with their chunks.
+pub fn prune_expired() {
+    let entries = match fs::read_dir(get_uploads_dir()) {
+        Ok(e) => e,
+        _ => return,
+    };
+    for entry in entries.flatten() {
+        if let Ok(id) = entry.file_name().into_string() {
+            // `load_session` removes the session if it has expired
+            if load_session(&id).is_err() && entry.path().join("session.json").exists() {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+}
+
+// The project's session that hasn't expired yet, if any
+fn open_session(project: &str) -> Option<UploadSession> {
+    fs::read_dir(get_uploads_dir())
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|id| load_session(&id).ok())
+        .find(|s| s.project == project)
+}
+
+// The chunks and the reassembled upload land in the root dir and the unpacked files in the
+// projects dir, which may be different filesystems
+async fn check_free_space(size: u64) -> Result<()> {
+    let size_mb = size.div_ceil(BYTES_PER_MB);
+    for (dir, needed_mb) in [(config_utils::root_dir(), size_mb * 2), (config_utils::projects_dir(), size_mb)] {
+        let free_mb = health_utils::free_disk_mb(dir)
+            .await
+            .map_err(|e| anyhow!("Failed to get the free disk space -> {e}"))?;
+        if free_mb < needed_mb {
+            return Err(anyhow!(
+                "Not enough free disk space for the upload, it needs {} MB and only {} MB are free",
+                needed_mb,
+                free_mb
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Starts a session for an upload of `size` bytes sent in chunks of `chunk_size` bytes.
+pub async fn start_session(project: &str, size: u64, chunk_size: u64, sha256: Option<String>) -> Result<UploadSession> {
+    let config = config_utils::get();
+    let max_size = config.uploads.max_size_mb * BYTES_PER_MB;
+    let max_chunk_size = config.server.upload_limit_mb * BYTES_PER_MB;
+    if size == 0 || size > max_size {
+        return Err(anyhow!(
+            "Upload size must be between 1 and {} bytes, got {}",
+            max_size,
+            size
+        ));
+    }
+    if chunk_size == 0 || chunk_size > max_chunk_size {
+        return Err(anyhow!(
+            "Chunk size must be between 1 and {} bytes, got {}",
+            max_chunk_size,
+            chunk_size
+        ));
+    }
+    if let Some(hash) = &sha256 {
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("`{}` is not a hex encoded sha256", hash));
+        }
+    }
+
+    prune_expired();
+    check_free_space(size).await?;
+
+    let _lock = START_LOCK.lock().map_err(|e| anyhow!("Failed to lock upload sessions -> {e}"))?;
+    if let Some(open) = open_session(project) {
+        return Err(anyhow!(
+            "An upload to project `{}` is already in progress (session `{}`)",
+            project,
+            open.id
+        ));
+    }
+
+    let id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_ID_LEN)
+        .map(char::from)
+        .collect();
+    let now = auth_utils::now_secs();
+    let session = UploadSession {
+        id: id.clone(),
+        project: project.to_string(),
+        size,
+        chunk_size,
+        chunk_count: size.div_ceil(chunk_size),
+        sha256: sha256.map(|h| h.to_ascii_lowercase()),
+        created_at: now,
+        expires_at: now + config.uploads.session_ttl_secs,
+    };
+
+    let dir = get_session_dir(&id)?;
+    fs::create_dir_all(&dir).map_err(|e| anyhow!("Failed to create `{dir}` -> {e}"))?;
+    if let Err(e) = save_session(&dir, &session) {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(e);
+    }
+    Ok(session)
+}
+
+/// Stores chunk `index` of the session after checking its length and sha256. Sending a chunk
+/// again replaces it.
+pub fn put_chunk(session: &UploadSession, index: u64, sha256: &str, data: &[u8]) -> Result<()> {
+    if index >= session.chunk_count {
+        return Err(anyhow!(
+            "Chunk {} is out of range, the upload has {} chunks",
+            index,
+            session.chunk_count
+        ));
+    }
+    let expected_len = session.expected_chunk_len(index);
+    if data.len() as u64 != expected_len {
+        return Err(anyhow!(
+            "Chunk {} must be {} bytes, got {}",
+            index,
+            expected_len,
+            data.len()
+        ));
+    }
+    if !auth_utils::constant_time_eq(
+        sha256.to_ascii_lowercase().as_bytes(),
+        signing_utils::sha256_hex(data).as_bytes(),
+    ) {
+        return Err(anyhow!("Chunk {} does not match its checksum", index));
+    }
+
+    let _guard = SessionGuard::acquire(session)?;
+    // Write then rename so an interrupted write never counts as a received chunk
+    let dir = get_session_dir(&session.id)?;
+    let path = get_chunk_path(&dir, index);
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, data).map_err(|e| anyhow!("Failed to write `{tmp_path}` -> {e}"))?;
+    fs::rename(&tmp_path, &path).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))
+}
+
+pub fn status(session: UploadSession) -> Result<UploadStatus> {
+    let dir = get_session_dir(&session.id)?;
+    let (received, missing) = (0..session.chunk_count).partition(|i| Path::new(&get_chunk_path(&dir, *i)).exists());
+    Ok(UploadStatus {
+        session,
+        received,
+        missing,
+    })
+}
+
+/// Reassembles the chunks and checks the upload against its sha256. The session is only removed
+/// once the upload matches its checksum and reads as a packet, until then the chunks are kept so
+/// the upload can be fixed and finalized again.
+pub fn finalize(session: &UploadSession) -> Result<BinaryPacket> {
+    let _guard = SessionGuard::acquire(session)?;
+    let dir = get_session_dir(&session.id)?;
+    let missing: Vec<u64> = (0..session.chunk_count)
+        .filter(|i| !Path::new(&get_chunk_path(&dir, *i)).exists())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Upload is incomplete, {} of {} chunks are missing",
+            missing.len(),
+            session.chunk_count
+        ));
+    }
+
+    let path = format!("{dir}/upload.bin");
+    let res = (|| {
+        let mut out = File::create(&path).map_err(|e| anyhow!("Failed to create `{path}` -> {e}"))?;
+        let mut hasher = Sha256::new();
+        for i in 0..session.chunk_count {
+            let chunk_path = get_chunk_path(&dir, i);
+            let chunk = fs::read(&chunk_path).map_err(|e| anyhow!("Failed to read `{chunk_path}` -> {e}"))?;
+            hasher.update(&chunk);
+            out.write_all(&chunk).map_err(|e| anyhow!("Failed to write `{path}` -> {e}"))?;
+        }
+        drop(out);
+
+        if let Some(expected) = &session.sha256 {
+            if hex::encode(hasher.finalize()) != *expected {
+                return Err(anyhow!("Upload does not match its checksum"));
+            }
+        }
+        let file = File::open(&path).map_err(|e| anyhow!("Failed to open `{path}` -> {e}"))?;
+        bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| anyhow!("Upload is not a valid packet -> {e}"))
+    })();
+
+    match res {
+        Ok(_) => {
+            let _ = fs::remove_dir_all(&dir);
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&path);
+        }
+    }
+    res
+}
+
+pub fn abort(session: &UploadSession) -> Result<()> {
+    let _guard = SessionGuard::acquire(session)?;
+    let dir = get_session_dir(&session.id)?;
+    fs::remove_dir_all(&dir).map_err(|e| anyhow!("Failed to remove `{dir}` -> {e}"))
+}